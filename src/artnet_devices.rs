@@ -0,0 +1,243 @@
+use derive_more::Display;
+use std::error::Error as StdError;
+use std::net::{ToSocketAddrs, SocketAddr, UdpSocket};
+use std::cmp::{ min };
+use log::{info, debug, error};
+use crate::configuration::BaseConfig;
+use crate::dmx_outputs::{DmxOutput, frame_for};
+use crate::shows::DmxUniverses;
+
+const ARTNET_ID: &[u8; 8] = b"Art-Net\0";
+const OP_DMX: u16 = 0x5000;
+const PROTOCOL_VERSION: u16 = 14;
+const DEFAULT_PORT: u16 = 6454;
+
+const MIN_FRAME_SIZE: usize = 2;
+const MAX_FRAME_SIZE: usize = 512;
+
+
+#[derive(Debug, Display)]
+pub enum Error {
+    IO(std::io::Error),
+    InvalidTarget(String),
+    PortClosed,
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::IO(e)
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        use Error::*;
+        match *self {
+            IO(ref e) => Some(e),
+            InvalidTarget(_) => None,
+            PortClosed => None,
+        }
+    }
+}
+
+pub struct ArtNet {
+    socket: Option<UdpSocket>,
+    target: String,
+    target_address: Option<SocketAddr>,
+    show_universe: u16,
+    port_address: u16,
+    sequence_enabled: bool,
+    sequence: u8,
+}
+
+impl ArtNet {
+    pub fn new(target_input: &str, show_universe: u16, port_address: u16, sequence_enabled: bool) -> ArtNet {
+        ArtNet {
+            socket: None,
+            target: String::from(target_input),
+            target_address: None,
+            show_universe,
+            port_address: port_address & 0x7FFF, // port addresses are 15 bit values
+            sequence_enabled,
+            sequence: 0,
+        }
+    }
+
+    pub fn open(&mut self) -> Result<(), Error> {
+        if self.socket.is_some() {
+            return Ok(());
+        }
+
+        let target_address = resolve_target(&self.target)?;
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+
+        self.target_address = Some(target_address);
+        self.socket = Some(socket);
+        Ok(())
+    }
+
+    fn next_sequence(&mut self) -> u8 {
+        if !self.sequence_enabled {
+            return 0;
+        }
+        // 0 disables the sequence feature on the receiving side, so valid numbers are 1-255
+        self.sequence = if self.sequence == 255 { 1 } else { self.sequence + 1 };
+        self.sequence
+    }
+
+    fn build_packet(&mut self, frame: &[u8]) -> Vec<u8> {
+        let input_size = min(frame.len(), MAX_FRAME_SIZE);
+        // ArtDmx requires an even data length between 2 and 512
+        let data_size = match input_size {
+            0..=MIN_FRAME_SIZE => MIN_FRAME_SIZE,
+            _ => input_size + input_size % 2,
+        };
+        let sequence = self.next_sequence();
+        let mut packet = Vec::with_capacity(18 + data_size);
+        packet.extend_from_slice(ARTNET_ID);
+        packet.extend_from_slice(&OP_DMX.to_le_bytes());
+        packet.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        packet.push(sequence);
+        packet.push(0); // physical input port, informational only
+        packet.push(self.port_address as u8); // subnet and universe
+        packet.push((self.port_address >> 8) as u8); // net
+        packet.extend_from_slice(&(data_size as u16).to_be_bytes());
+        packet.extend_from_slice(&frame[0..input_size]);
+        packet.resize(18 + data_size, 0);
+        packet
+    }
+
+    fn write_packet(&mut self, packet: &[u8]) -> Result<(), Error> {
+        let socket = self.socket.as_ref().ok_or(Error::PortClosed)?;
+        let target_address = self.target_address.ok_or(Error::PortClosed)?;
+        socket.send_to(packet, target_address)?;
+        Ok(())
+    }
+
+    pub fn write_frame(&mut self, frame: &[u8]) {
+        if frame.len() > MAX_FRAME_SIZE {
+            debug!("Frame data too large, cutting off excess data");
+        }
+        let packet = self.build_packet(frame);
+        let written = self.write_packet(&packet);
+        match written {
+            Ok(()) => (),
+            Err(error) => error!("Frame was not successfully sent to Art-Net node: {:?}", error)
+        }
+    }
+}
+
+impl DmxOutput for ArtNet {
+    fn write(&mut self, universes: &DmxUniverses) {
+        self.write_frame(frame_for(universes, self.show_universe));
+    }
+}
+
+fn resolve_target(target: &str) -> Result<SocketAddr, Error> {
+    // Targets may be given with or without port, Art-Net uses 6454 by default
+    let resolved = if target.contains(':') {
+        target.to_socket_addrs()
+    } else {
+        (target, DEFAULT_PORT).to_socket_addrs()
+    };
+    match resolved {
+        Ok(mut addresses) => addresses.next().ok_or(Error::InvalidTarget(String::from(target))),
+        Err(_) => Err(Error::InvalidTarget(String::from(target))),
+    }
+}
+
+fn port_address(net: u8, subnet: u8, universe: u8) -> u16 {
+    // net is a 7 bit value, subnet and universe are 4 bit values
+    ((net as u16 & 0x7F) << 8) | ((subnet as u16 & 0x0F) << 4) | (universe as u16 & 0x0F)
+}
+
+pub fn open_artnet_ports(config: &BaseConfig) -> Result<Vec<ArtNet>, Box<dyn StdError>> {
+    // The configured net, subnet and universe are used for the first show universe, following ones count up from there
+    let base_address = port_address(config.artnet_net, config.artnet_subnet, config.artnet_universe);
+    let mut ports = Vec::new();
+    for show_universe in config.artnet_universes.iter() {
+        if *show_universe < 1 {
+            error!("");
+            error!("!!  Invalid universe {} in artnet_universes, universes are numbered from 1.  !!", show_universe);
+            error!("");
+            return Err("".into());
+        }
+        let address = base_address.wrapping_add(show_universe - 1) & 0x7FFF;
+        let mut artnet = ArtNet::new(&config.artnet_target, *show_universe, address, config.artnet_sequence);
+        let opened = artnet.open();
+        if let Err(error) = opened {
+            error!("");
+            error!("!!  Couldn't open Art-Net output to '{}': {:?}  !!", config.artnet_target, error);
+            error!("    Check the artnet_target in your config, e.g. '255.255.255.255' or '2.0.0.10:6454'.");
+            error!("");
+            return Err("".into());
+        }
+        info!("Opened Art-Net output:   {} (universe {} -> net {}, subnet {}, universe {})",
+            config.artnet_target, show_universe, (address >> 8) & 0x7F, (address >> 4) & 0x0F, address & 0x0F);
+        ports.push(artnet);
+    }
+    Ok(ports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn receive(socket: &UdpSocket) -> Vec<u8> {
+        let mut buffer = [0; 1024];
+        let (size, _) = socket.recv_from(&mut buffer).unwrap();
+        buffer[..size].to_vec()
+    }
+
+    #[test]
+    fn sends_art_dmx_to_the_target() {
+        let receiver = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let target = receiver.local_addr().unwrap().to_string();
+        let mut artnet = ArtNet::new(&target, 1, port_address(1, 2, 3), true);
+        artnet.open().unwrap();
+
+        artnet.write_frame(&[10, 20, 30]);
+        let packet = receive(&receiver);
+        assert_eq!(&packet[0..8], b"Art-Net\0");
+        assert_eq!(packet[8..10], [0x00, 0x50]); // OpDmx, little endian
+        assert_eq!(packet[10..12], [0, 14]); // protocol version, big endian
+        assert_eq!(packet[12], 1); // sequence
+        assert_eq!(packet[13], 0); // physical
+        assert_eq!(packet[14..16], [0x23, 0x01]); // subnet and universe, net
+        assert_eq!(packet[16..18], [0, 4]); // odd lengths are padded to an even length
+        assert_eq!(packet[18..], [10, 20, 30, 0]);
+
+        artnet.write_frame(&[0; 600]);
+        let packet = receive(&receiver);
+        assert_eq!(packet[12], 2);
+        assert_eq!(packet[16..18], [0x02, 0x00]);
+        assert_eq!(packet.len(), 18 + MAX_FRAME_SIZE);
+    }
+
+    #[test]
+    fn short_frames_and_disabled_sequence() {
+        let mut artnet = ArtNet::new("127.0.0.1", 1, 0, false);
+        let packet = artnet.build_packet(&[]);
+        assert_eq!(packet[12], 0);
+        assert_eq!(packet[16..18], [0, 2]);
+        assert_eq!(packet.len(), 18 + MIN_FRAME_SIZE);
+    }
+
+    #[test]
+    fn sequence_wraps_to_1() {
+        let mut artnet = ArtNet::new("127.0.0.1", 1, 0, true);
+        artnet.sequence = 255;
+        assert_eq!(artnet.next_sequence(), 1);
+    }
+
+    #[test]
+    fn port_address_is_masked() {
+        assert_eq!(port_address(0x7F, 0x0F, 0x0F), 0x7FFF);
+        assert_eq!(port_address(0xFF, 0x1F, 0x1F), 0x7FFF);
+        assert_eq!(resolve_target("127.0.0.1").unwrap().port(), DEFAULT_PORT);
+    }
+}
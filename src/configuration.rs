@@ -1,58 +1,282 @@
 extern crate confy;
 use serde::{Serialize, Deserialize};
-use log::{info};
+use log::{info, error};
+use std::path::PathBuf;
+use crossbeam_channel::{unbounded, Receiver};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use log::LevelFilter;
 use log4rs::append::console::ConsoleAppender;
 use log4rs::config::{Appender, Root};
 use log4rs::Config;
 use log4rs::encode::pattern::PatternEncoder;
+use std::error::Error;
+
+const MIN_FPS: u64 = 1;
+const MAX_FPS: u64 = 1000; // a frame has to last at least a millisecond
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct BaseConfig {
     version: u8,
+    pub dmx_outputs: Vec<String>,
     pub dmx_serial_port_win: String,
     pub dmx_serial_port_osx: String,
     pub dmx_serial_port_other: String,
+    pub dmx_usb_vid: String,
+    pub dmx_usb_pid: String,
+    pub dmx_usb_serial: String,
+    pub dmx_verify_widget: bool,
+    pub dmx_break_time: u8,
+    pub dmx_mark_after_break_time: u8,
+    pub dmx_output_rate: u8,
+    pub dmx_input: bool,
+    pub dmx_input_merge: bool,
+    pub dmx_input_universe: u16,
+    pub dmx_capture_path: String,
+    pub enttec_universe: u16,
+    pub enttec_universe_2: u16,
+    pub dmx_mk2_api_key: String,
+    pub dmx_mk2_api_key_label: u8,
+    pub dmx_mk2_port_assignment_label: u8,
+    pub dmx_mk2_send_dmx_2_label: u8,
+    pub open_dmx_serial_port: String,
+    pub open_dmx_universe: u16,
+    pub artnet_target: String,
+    pub artnet_net: u8,
+    pub artnet_subnet: u8,
+    pub artnet_universe: u8,
+    pub artnet_sequence: bool,
+    pub artnet_universes: Vec<u16>,
+    pub sacn_destination: String,
+    pub sacn_universe: u16,
+    pub sacn_priority: u8,
+    pub sacn_source_name: String,
+    pub sacn_cid: String,
+    pub sacn_universes: Vec<u16>,
+    pub recorder_path: String,
+    pub recorder_universes: Vec<u16>,
+    pub virtual_universes: Vec<u16>,
+    pub virtual_print_changes: bool,
     pub show_path: String,
     pub midi_channel: u8,
+    pub midi_mode: String,
     pub midi_port: String,
+    pub midi_virtual_port: String,
     pub midi_faders: bool,
+    pub midi_clock: bool,
+    pub midi_timecode: bool,
+    pub midi_learn: String,
+    pub midi_feedback_port: String,
+    pub midi_feedback_channel: u8,
+    pub midi_feedback_song: String,
+    pub midi_feedback_song_number: u8,
+    pub midi_feedback_scene: String,
+    pub midi_feedback_scene_number: u8,
+    pub midi_feedback_tempo: String,
+    pub midi_feedback_tempo_number: u8,
+    pub midi_tempo_mode: String,
+    pub tap_tempo_message: String,
+    pub tap_tempo_number: u8,
+    pub tap_tempo_taps: u8,
+    pub tap_tempo_timeout_ms: u64,
+    pub beats_per_bar: u8,
+    pub beat_align: String,
+    pub scene_quantize: String,
     pub fps: u64,
     pub log_level: String,
+    // Tables have to come last in the config file and empty ones are left out, a value after a table can't be written
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub midi_inputs: Vec<MidiInputPort>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub midi_mappings: Vec<MidiMapping>,
+}
+
+// An input port with the channels it listens on, no channels means all channels (omni)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MidiInputPort {
+    pub port: String,
+    pub virtual_port: String,
+    pub channels: Vec<u8>,
+}
+
+// Binds a MIDI message to an action, see the MIDI section of the README
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MidiMapping {
+    pub action: String,
+    pub message: String,
+    pub channel: u8,
+    pub number: Option<u8>,
+    pub value: Option<usize>,
 }
 
 impl Default for BaseConfig {
     fn default() -> Self {
         BaseConfig {
             version: 0,
+            dmx_outputs: vec![String::from("enttec")],
             dmx_serial_port_win: String::from("COM-1"),
             dmx_serial_port_osx: String::from("/dev/tty.usbserial-ENVVVC0F"),
             dmx_serial_port_other: String::from("/dev/ttyUSB0"),
+            dmx_usb_vid: String::new(),
+            dmx_usb_pid: String::new(),
+            dmx_usb_serial: String::new(),
+            dmx_verify_widget: true,
+            dmx_break_time: 9,
+            dmx_mark_after_break_time: 1,
+            dmx_output_rate: 40,
+            dmx_input: false,
+            dmx_input_merge: true,
+            dmx_input_universe: 1,
+            dmx_capture_path: String::from("captures"),
+            enttec_universe: 1,
+            enttec_universe_2: 0,
+            dmx_mk2_api_key: String::new(),
+            dmx_mk2_api_key_label: 0,
+            dmx_mk2_port_assignment_label: 0,
+            dmx_mk2_send_dmx_2_label: 0,
+            open_dmx_serial_port: String::from("/dev/ttyUSB1"),
+            open_dmx_universe: 1,
+            artnet_target: String::from("255.255.255.255"),
+            artnet_net: 0,
+            artnet_subnet: 0,
+            artnet_universe: 0,
+            artnet_sequence: true,
+            artnet_universes: vec![1],
+            sacn_destination: String::new(),
+            sacn_universe: 1,
+            sacn_priority: 100,
+            sacn_source_name: String::from("Rustylight"),
+            sacn_cid: String::new(),
+            sacn_universes: vec![1],
+            recorder_path: String::from("recording.txt"),
+            recorder_universes: vec![1],
+            virtual_universes: vec![1],
+            virtual_print_changes: false,
             show_path: String::from("default_show"),
             midi_channel: 1,
+            midi_mode: String::from("port"),
             midi_port: String::from("M-Audio MIDISPORT Uno"),
+            midi_virtual_port: String::from("Rustylight"),
             midi_faders: false,
+            midi_clock: true,
+            midi_timecode: true,
+            midi_learn: String::new(),
+            midi_feedback_port: String::new(),
+            midi_feedback_channel: 1,
+            midi_feedback_song: String::from("cc"),
+            midi_feedback_song_number: 0,
+            midi_feedback_scene: String::from("pc"),
+            midi_feedback_scene_number: 0,
+            midi_feedback_tempo: String::from("none"),
+            midi_feedback_tempo_number: 0,
+            midi_tempo_mode: String::from("sum"),
+            tap_tempo_message: String::from("none"),
+            tap_tempo_number: 0,
+            tap_tempo_taps: 4,
+            tap_tempo_timeout_ms: 2000,
+            beats_per_bar: 4,
+            beat_align: String::from("off"),
+            scene_quantize: String::from("off"),
             fps: 20,
             log_level: String::from("info"),
+            midi_inputs: Vec::new(),
+            midi_mappings: Vec::new(),
         }
     }
 }
 
-pub fn load() -> Result<BaseConfig, confy::ConfyError> {
+pub fn load() -> Result<BaseConfig, Box<dyn Error>> {
     let config = confy::load("rusty-light", None);
     let config_path = confy::get_configuration_file_path("rusty-light", None);
     println!("Config location:         {}", config_path?.into_os_string().into_string().unwrap());
     if config.is_err() {
         println!("Couldn't load config. Make sure your config file contains all required fields.");
-        config
+        config.map_err(|err| err.into())
     } else {
         let config_result = config.unwrap();
         set_up_logging(&config_result);
+        if !is_valid(&config_result) {
+            return Err("".into());
+        }
         info!("Config loaded:           Done");
         Ok(config_result)
     }
 }
 
+// Loads the config again while the show is running. Logging is already set up at this point.
+pub fn reload() -> Option<BaseConfig> {
+    match confy::load("rusty-light", None) {
+        Ok(config) if is_valid(&config) => Some(config),
+        Ok(_) => {
+            error!("Keeping the current config");
+            None
+        },
+        Err(err) => {
+            error!("Couldn't reload config, keeping the current one: {}", err);
+            None
+        }
+    }
+}
+
+// Checks settings a running show can't cope with, the others are validated where they are used
+fn is_valid(config: &BaseConfig) -> bool {
+    if !(MIN_FPS..=MAX_FPS).contains(&config.fps) {
+        error!("");
+        error!("!!  Invalid fps {}, use {}-{}.  !!", config.fps, MIN_FPS, MAX_FPS);
+        error!("");
+        return false;
+    }
+    true
+}
+
+// Writes the config back, e.g. after learning a midi mapping. The watcher picks up the change like a manual edit.
+pub fn store(config: &BaseConfig) {
+    if let Err(err) = confy::store("rusty-light", None, config) {
+        error!("Couldn't store config: {}", err);
+    }
+}
+
+pub struct ConfigWatcher {
+    _watcher: RecommendedWatcher,
+    receiver: Receiver<notify::Result<Event>>,
+    config_path: PathBuf,
+}
+
+impl ConfigWatcher {
+    pub fn has_changed(&self) -> bool {
+        let mut changed = false;
+        while let Ok(event) = self.receiver.try_recv() {
+            if let Ok(event) = event {
+                let is_write = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_));
+                if is_write && event.paths.iter().any(|path| path.ends_with(self.config_path.file_name().unwrap_or_default())) {
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+}
+
+pub fn watch() -> Option<ConfigWatcher> {
+    let config_path = confy::get_configuration_file_path("rusty-light", None).ok()?;
+    // Editors often replace the file instead of writing to it, so the whole directory is watched
+    let config_dir = config_path.parent()?.to_path_buf();
+    let (sender, receiver) = unbounded();
+    let watcher = notify::recommended_watcher(sender).and_then(|mut watcher| {
+        watcher.watch(&config_dir, RecursiveMode::NonRecursive)?;
+        Ok(watcher)
+    });
+    match watcher {
+        Ok(watcher) => Some(ConfigWatcher { _watcher: watcher, receiver, config_path }),
+        Err(err) => {
+            error!("Couldn't watch the config for changes: {}", err);
+            None
+        }
+    }
+}
+
 fn set_up_logging(config: &BaseConfig) {
     let log_level = match config.log_level.as_str() {
         "info" => LevelFilter::Info,
@@ -74,3 +298,17 @@ fn set_up_logging(config: &BaseConfig) {
         .unwrap();
     let _handle = log4rs::init_config(config).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fps_has_to_leave_at_least_a_millisecond_per_frame() {
+        let mut config = BaseConfig::default();
+        for (fps, valid) in [(0, false), (1, true), (1000, true), (1001, false)] {
+            config.fps = fps;
+            assert_eq!(is_valid(&config), valid, "fps {}", fps);
+        }
+    }
+}
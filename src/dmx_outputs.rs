@@ -0,0 +1,69 @@
+use std::error::Error as StdError;
+use log::{error};
+use crate::configuration::BaseConfig;
+use crate::shows::{DmxUniverses, UNIVERSE_SIZE};
+use crate::enttec_devices::{open_dmxis_port, open_open_dmx_port};
+use crate::artnet_devices::open_artnet_ports;
+use crate::sacn_devices::open_sacn_ports;
+use crate::file_recorder::open_file_recorder;
+use crate::virtual_devices::open_virtual_port;
+
+const BLANK_FRAME: [u8; UNIVERSE_SIZE] = [0; UNIVERSE_SIZE];
+
+// Every output receives all rendered universes once per frame and picks the ones it sends.
+// Outputs handle their own errors so a failing output doesn't block the others.
+pub trait DmxOutput {
+    fn write(&mut self, universes: &DmxUniverses);
+
+    fn update_config(&mut self, _config: &BaseConfig) {}
+
+    // Outputs that can also receive DMX return the latest received frame
+    fn read_input(&mut self) -> Option<[u8; UNIVERSE_SIZE]> {
+        None
+    }
+
+    fn close(&mut self) {}
+}
+
+// Universes not used by the show are sent as blackout so outputs never keep stale values
+pub fn frame_for(universes: &DmxUniverses, universe: u16) -> &[u8] {
+    match universes.get(&universe) {
+        Some(frame) => frame,
+        None => &BLANK_FRAME,
+    }
+}
+
+pub fn open_dmx_outputs(config: &BaseConfig) -> Result<Vec<Box<dyn DmxOutput>>, Box<dyn StdError>> {
+    let mut outputs: Vec<Box<dyn DmxOutput>> = Vec::new();
+    for output in config.dmx_outputs.iter() {
+        match output.as_str() {
+            "enttec" => outputs.push(Box::new(open_dmxis_port(config)?)),
+            "opendmx" => outputs.push(Box::new(open_open_dmx_port(config)?)),
+            "artnet" => {
+                for artnet in open_artnet_ports(config)? {
+                    outputs.push(Box::new(artnet));
+                }
+            },
+            "sacn" => {
+                for sacn in open_sacn_ports(config)? {
+                    outputs.push(Box::new(sacn));
+                }
+            },
+            "recorder" => outputs.push(Box::new(open_file_recorder(config)?)),
+            "virtual" => outputs.push(Box::new(open_virtual_port(config))),
+            _ => {
+                error!("");
+                error!("!!  Unknown dmx output '{}', use 'enttec', 'opendmx', 'artnet', 'sacn', 'recorder' or 'virtual'.  !!", output);
+                error!("");
+                return Err("".into());
+            }
+        }
+    }
+    if outputs.is_empty() {
+        error!("");
+        error!("!!  No dmx output configured, add at least one to dmx_outputs in your config.  !!");
+        error!("");
+        return Err("".into());
+    }
+    Ok(outputs)
+}
@@ -1,18 +1,43 @@
 use derive_more::Display;
 use serialport::Error as SerialError;
 use std::error::Error as StdError;
-use serialport::{ new, available_ports, SerialPort, SerialPortType };
+use serialport::{ new, available_ports, ClearBuffer, DataBits, FlowControl, Parity, SerialPort, SerialPortType, StopBits, UsbPortInfo };
 use core::time::Duration;
+use std::time::Instant;
+use std::thread::sleep;
 use std::cmp::{ min };
-use log::{info, debug, error};
+use log::{info, debug, warn, error};
 use crate::configuration::BaseConfig;
+use crate::dmx_outputs::{DmxOutput, frame_for};
+use crate::shows::{DmxUniverses, UNIVERSE_SIZE};
 
+const GET_PARAMETERS_COMMAND: u8 = 3;
 const SET_PARAMETERS_COMMAND: u8 = 4;
+const RECEIVED_DMX_COMMAND: u8 = 5;
 const SEND_PACKET_COMMAND: u8 = 6;
+const RECEIVE_DMX_ON_CHANGE_COMMAND: u8 = 8;
+const GET_SERIAL_NUMBER_COMMAND: u8 = 10;
 
 const START_VAL: u8 = 0x7E;
 const END_VAL: u8 = 0xE7;
 
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+const REPLY_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_PACKET_SIZE: usize = 600;
+const TIME_UNIT_MICROSECONDS: f64 = 10.67; // break and mark after break are given in units of 10.67us
+
+// allowed ranges for the widget parameters according to the Enttec Pro API
+const MIN_BREAK_TIME: u8 = 9;
+const MAX_BREAK_TIME: u8 = 127;
+const MIN_MARK_AFTER_BREAK_TIME: u8 = 1;
+const MAX_MARK_AFTER_BREAK_TIME: u8 = 127;
+const MAX_OUTPUT_RATE: u8 = 40;
+
+// Open DMX dongles have no microcontroller, so the host generates the DMX signal itself
+const OPEN_DMX_BAUD_RATE: u32 = 250000;
+const OPEN_DMX_BREAK_TIME: Duration = Duration::from_micros(110);
+const OPEN_DMX_MARK_AFTER_BREAK_TIME: Duration = Duration::from_micros(16);
+
 const MIN_FRAME_SIZE: usize = 24;
 const MAX_FRAME_SIZE: usize = 512;
 
@@ -22,6 +47,9 @@ pub enum Error {
     Serial(SerialError),
     IO(std::io::Error),
     PortClosed,
+    DeviceNotFound,
+    NoReply,
+    InvalidParameters,
 }
 
 impl From<SerialError> for Error {
@@ -43,45 +71,308 @@ impl StdError for Error {
             Serial(ref e) => Some(e),
             IO(ref e) => Some(e),
             PortClosed => None,
+            DeviceNotFound => None,
+            NoReply => None,
+            InvalidParameters => None,
         }
     }
 }
 
+// Identifies a widget by its USB descriptors instead of the OS specific port name
+pub struct UsbFilter {
+    vendor_id: Option<u16>,
+    product_id: Option<u16>,
+    serial_number: Option<String>,
+}
+
+impl UsbFilter {
+    fn matches(&self, info: &UsbPortInfo) -> bool {
+        self.vendor_id.map_or(true, |vendor_id| vendor_id == info.vid)
+            && self.product_id.map_or(true, |product_id| product_id == info.pid)
+            && self.serial_number.as_ref().map_or(true, |serial_number| info.serial_number.as_ref() == Some(serial_number))
+    }
+
+    fn find_port_name(&self) -> Option<String> {
+        let mut port_names: Vec<String> = available_ports().ok()?
+            .into_iter()
+            .filter(|p| match &p.port_type {
+                SerialPortType::UsbPort(usb_port_info) => self.matches(usb_port_info),
+                _ => false,
+            })
+            .map(|p| p.port_name)
+            .collect();
+        port_names.sort();
+        port_names.into_iter().next()
+    }
+}
+
+pub struct WidgetParameters {
+    firmware_version: (u8, u8),
+    break_time: u8,
+    mark_after_break_time: u8,
+    output_rate: u8,
+}
+
+// The DMX USB Pro Mk2 unlocks its second port with an API key. The key and the labels used for the
+// second port are handed out by Enttec per developer, so they have to be provided via the config.
+pub struct SecondPort {
+    universe: u16,
+    api_key: [u8; 4],
+    api_key_command: u8,
+    port_assignment_command: u8,
+    send_packet_command: u8,
+}
+
 pub struct Dmxis {
     port: Option<Box<dyn SerialPort>>,
+    second_port: Option<SecondPort>,
+    read_buffer: Vec<u8>,
+    verify_widget: bool,
     port_name: String,
+    usb_filter: Option<UsbFilter>,
+    universe: u16,
     break_time: u8,
     mark_after_break_time: u8,
-    output_rate: u8
+    output_rate: u8,
+    last_reconnect_attempt: Option<Instant>,
+    input_enabled: bool,
+    input_frame: Option<[u8; UNIVERSE_SIZE]>,
 }
 
 impl Dmxis {
-    pub fn new(port_name_input: &str) -> Dmxis {
+    pub fn new(port_name_input: &str, usb_filter: Option<UsbFilter>, universe: u16, verify_widget: bool) -> Dmxis {
         return Dmxis{
             port: None,
+            second_port: None,
+            read_buffer: Vec::new(),
+            verify_widget,
             port_name: String::from(port_name_input),
+            usb_filter,
+            universe,
             break_time: 9, //DMX protocol defines a break to indicate the beginnging of a packet
             mark_after_break_time: 1, //DMX protocol defines a mark after break to indicate the beginnging of a packet
-            output_rate: 40 //fps
+            output_rate: 40, //fps
+            last_reconnect_attempt: None,
+            input_enabled: false,
+            input_frame: None,
         };
     }
 
     pub fn open(&mut self) -> Result<(), Error> {
+        self.open_port(true)
+    }
+
+    // Querying waits for the widget's replies, which reconnecting in the frame loop can't afford
+    fn open_port(&mut self, query: bool) -> Result<(), Error> {
         if self.port.is_some() {
             return Ok(());
         }
 
+        // The device may enumerate under a different name after being replugged, so look it up on every open
+        if let Some(usb_filter) = &self.usb_filter {
+            self.port_name = usb_filter.find_port_name().ok_or(Error::DeviceNotFound)?;
+        }
+
         let port = new(&self.port_name, 57600)
             .timeout(Duration::from_millis(1))
             .open()?;
 
+        port.clear(ClearBuffer::Input)?;
         self.port = Some(port);
+        self.read_buffer.clear();
+
+        // ask the widget about itself to be sure it speaks the Enttec Pro protocol
+        if query {
+            if let Err(e) = self.query_widget() {
+                if self.verify_widget {
+                    self.port = None;
+                    return Err(e);
+                }
+                warn!("DMX widget on {} didn't answer, it may not be Enttec Pro compatible", self.port_name);
+            }
+        }
 
         // send the default parameters to the port
         if let Err(e) = self.set_dmx_params() {
             self.port = None;
             return Err(e);
         }
+        if let Err(e) = self.set_receive_mode() {
+            self.port = None;
+            return Err(e);
+        }
+        if self.second_port.is_some() {
+            if let Err(e) = self.enable_second_port() {
+                self.port = None;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn query_widget(&mut self) -> Result<(), Error> {
+        let parameters = self.get_widget_params()?;
+        let serial_number = self.get_serial_number()?;
+        info!("DMX widget serial number: {}", serial_number);
+        info!("DMX widget firmware:     {}.{}", parameters.firmware_version.0, parameters.firmware_version.1);
+        info!("DMX widget timing:       break {:.0}us, mark after break {:.0}us, {} packets/s",
+            parameters.break_time as f64 * TIME_UNIT_MICROSECONDS,
+            parameters.mark_after_break_time as f64 * TIME_UNIT_MICROSECONDS,
+            parameters.output_rate);
+        Ok(())
+    }
+
+    pub fn get_widget_params(&mut self) -> Result<WidgetParameters, Error> {
+        // the request contains the size of the user configuration to read, which isn't used here
+        self.write_packet(GET_PARAMETERS_COMMAND, &[0, 0], false)?;
+        let reply = self.read_packet(GET_PARAMETERS_COMMAND)?;
+        if reply.len() < 5 {
+            return Err(Error::NoReply);
+        }
+        Ok(WidgetParameters {
+            firmware_version: (reply[1], reply[0]),
+            break_time: reply[2],
+            mark_after_break_time: reply[3],
+            output_rate: reply[4],
+        })
+    }
+
+    pub fn get_serial_number(&mut self) -> Result<String, Error> {
+        self.write_packet(GET_SERIAL_NUMBER_COMMAND, &[], false)?;
+        let reply = self.read_packet(GET_SERIAL_NUMBER_COMMAND)?;
+        if reply.len() < 4 {
+            return Err(Error::NoReply);
+        }
+        // the serial number is BCD encoded with the least significant byte first
+        Ok(format!("{:02x}{:02x}{:02x}{:02x}", reply[3], reply[2], reply[1], reply[0]))
+    }
+
+    fn read_available(&mut self) -> Result<(), Error> {
+        let port = self.port.as_mut().ok_or(Error::PortClosed)?;
+        let available = port.bytes_to_read()? as usize;
+        if available > 0 {
+            let mut buffer = vec![0; available];
+            let read = port.read(&mut buffer)?;
+            self.read_buffer.extend_from_slice(&buffer[..read]);
+        }
+        Ok(())
+    }
+
+    // Takes the next complete message from the read buffer, skipping anything that isn't properly framed
+    fn next_received_packet(&mut self) -> Option<(u8, Vec<u8>)> {
+        loop {
+            let start = self.read_buffer.iter().position(|byte| *byte == START_VAL)?;
+            self.read_buffer.drain(..start);
+            if self.read_buffer.len() < 4 {
+                return None;
+            }
+            let command = self.read_buffer[1];
+            let paket_size = self.read_buffer[2] as usize | (self.read_buffer[3] as usize) << 8;
+            if paket_size > MAX_PACKET_SIZE {
+                self.read_buffer.drain(..1);
+                continue;
+            }
+            if self.read_buffer.len() < paket_size + 5 {
+                return None;
+            }
+            if self.read_buffer[paket_size + 4] != END_VAL {
+                self.read_buffer.drain(..1);
+                continue;
+            }
+            let paket = self.read_buffer[4..paket_size + 4].to_vec();
+            self.read_buffer.drain(..paket_size + 5);
+            return Some((command, paket));
+        }
+    }
+
+    fn read_packet(&mut self, command: u8) -> Result<Vec<u8>, Error> {
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        while Instant::now() < deadline {
+            self.read_available()?;
+            while let Some((received_command, paket)) = self.next_received_packet() {
+                if received_command == command {
+                    return Ok(paket);
+                }
+            }
+            sleep(Duration::from_millis(1));
+        }
+        Err(Error::NoReply)
+    }
+
+    pub fn set_timing(&mut self, break_time: u8, mark_after_break_time: u8, output_rate: u8) -> Result<(), Error> {
+        if !(MIN_BREAK_TIME..=MAX_BREAK_TIME).contains(&break_time)
+            || !(MIN_MARK_AFTER_BREAK_TIME..=MAX_MARK_AFTER_BREAK_TIME).contains(&mark_after_break_time)
+            || output_rate > MAX_OUTPUT_RATE {
+            return Err(Error::InvalidParameters);
+        }
+        if break_time == self.break_time && mark_after_break_time == self.mark_after_break_time && output_rate == self.output_rate {
+            return Ok(());
+        }
+        self.break_time = break_time;
+        self.mark_after_break_time = mark_after_break_time;
+        self.output_rate = output_rate;
+        // an unopened port gets the parameters as soon as it's opened
+        if self.port.is_some() {
+            self.set_dmx_params()?;
+        }
+        Ok(())
+    }
+
+    pub fn set_second_port(&mut self, second_port: Option<SecondPort>) {
+        self.second_port = second_port;
+    }
+
+    fn enable_second_port(&mut self) -> Result<(), Error> {
+        let second_port = self.second_port.as_ref().ok_or(Error::PortClosed)?;
+        let api_key = second_port.api_key;
+        let api_key_command = second_port.api_key_command;
+        let port_assignment_command = second_port.port_assignment_command;
+        self.write_packet(api_key_command, &api_key, false)?;
+        // both ports are assigned to DMX output
+        self.write_packet(port_assignment_command, &[1, 1], false)
+    }
+
+    pub fn set_input(&mut self, enabled: bool) -> Result<(), Error> {
+        if enabled == self.input_enabled {
+            return Ok(());
+        }
+        self.input_enabled = enabled;
+        if !enabled {
+            self.input_frame = None;
+        }
+        if self.port.is_some() {
+            self.set_receive_mode()?;
+        }
+        Ok(())
+    }
+
+    fn set_receive_mode(&mut self) -> Result<(), Error> {
+        // 0 lets the widget send every received packet, 1 only sends changes. The widget can't be told to stop
+        // sending, so a disabled input falls back to changes only and read_input discards what still arrives.
+        let mode = if self.input_enabled { 0 } else { 1 };
+        self.write_packet(RECEIVE_DMX_ON_CHANGE_COMMAND, &[mode], false)
+    }
+
+    // Reads all packets the widget sent since the last frame and keeps the latest received DMX frame
+    fn read_received_dmx(&mut self) -> Result<(), Error> {
+        self.read_available()?;
+        while let Some((command, paket)) = self.next_received_packet() {
+            if !self.input_enabled || command != RECEIVED_DMX_COMMAND || paket.len() < 2 {
+                continue;
+            }
+            // the first byte holds error flags, the second the start code which has to be 0 for dimmer data
+            if paket[0] != 0 {
+                debug!("Received DMX packet with errors: {:#04x}", paket[0]);
+                continue;
+            }
+            if paket[1] != 0 {
+                continue;
+            }
+            let mut frame = [0; UNIVERSE_SIZE];
+            let slots = min(paket.len() - 2, UNIVERSE_SIZE);
+            frame[..slots].copy_from_slice(&paket[2..slots + 2]);
+            self.input_frame = Some(frame);
+        }
         Ok(())
     }
 
@@ -116,7 +407,36 @@ impl Dmxis {
         Ok(())
     }
 
-    pub fn write(&mut self, frame: &[u8]) {
+    // Retries to open the port after the connection got lost, e.g. because the cable got unplugged
+    fn reconnect(&mut self) -> bool {
+        if let Some(last_attempt) = self.last_reconnect_attempt {
+            if last_attempt.elapsed() < RECONNECT_INTERVAL {
+                return false;
+            }
+        }
+        self.last_reconnect_attempt = Some(Instant::now());
+        // the widget was already queried when it was first opened, skipping it keeps the other outputs running
+        match self.open_port(false) {
+            Ok(()) => {
+                info!("Reconnected DMX serial port: {}", self.port_name);
+                self.last_reconnect_attempt = None;
+                true
+            },
+            Err(error) => {
+                debug!("Reconnecting DMX serial port {} failed: {:?}", self.port_name, error);
+                false
+            }
+        }
+    }
+
+    pub fn write_frame(&mut self, frame: &[u8]) {
+        if self.port.is_none() && !self.reconnect() {
+            return;
+        }
+        self.write_dmx(SEND_PACKET_COMMAND, frame);
+    }
+
+    fn write_dmx(&mut self, command: u8, frame: &[u8]) {
         let input_size = frame.len();
         let capacity = match input_size {
             0..=MIN_FRAME_SIZE => MIN_FRAME_SIZE,
@@ -129,10 +449,15 @@ impl Dmxis {
         let mut padded_frame = Vec::with_capacity(capacity);
         padded_frame.extend_from_slice(&frame[0..min(input_size, capacity)]);
         padded_frame.resize(capacity, 0);
-        let written = self.write_packet(SEND_PACKET_COMMAND, &padded_frame, true);
+        let written = self.write_packet(command, &padded_frame, true);
         match written {
             Ok(()) => (),
-            Err(error) => error!("Frame was not successfully written to DMXIS: {:?}", error)
+            Err(error) => {
+                error!("Frame was not successfully written to DMXIS: {:?}", error);
+                warn!("Closed DMX serial port {}, retrying to open it every {}s", self.port_name, RECONNECT_INTERVAL.as_secs());
+                self.port = None;
+                self.last_reconnect_attempt = Some(Instant::now());
+            }
         }
     }
 
@@ -148,6 +473,140 @@ impl Dmxis {
     // }
 }
 
+impl DmxOutput for Dmxis {
+    fn write(&mut self, universes: &DmxUniverses) {
+        self.write_frame(frame_for(universes, self.universe));
+        if self.port.is_none() {
+            return;
+        }
+        if let Some(second_port) = &self.second_port {
+            let command = second_port.send_packet_command;
+            let universe = second_port.universe;
+            self.write_dmx(command, frame_for(universes, universe));
+        }
+    }
+
+    fn update_config(&mut self, config: &BaseConfig) {
+        match self.set_timing(config.dmx_break_time, config.dmx_mark_after_break_time, config.dmx_output_rate) {
+            Ok(()) => debug!("DMX timing:              break {}, mark after break {}, rate {}", self.break_time, self.mark_after_break_time, self.output_rate),
+            Err(Error::InvalidParameters) => log_invalid_timing(config),
+            Err(error) => error!("DMX timing couldn't be sent to the widget: {:?}", error),
+        }
+        if let Err(error) = self.set_input(config.dmx_input) {
+            error!("DMX input couldn't be switched on the widget: {:?}", error);
+        }
+    }
+
+    fn read_input(&mut self) -> Option<[u8; UNIVERSE_SIZE]> {
+        self.port.as_ref()?;
+        // Packets are drained even while the input is disabled, otherwise they would pile up in the buffers
+        if let Err(error) = self.read_received_dmx() {
+            error!("DMX input couldn't be read from the widget: {:?}", error);
+        }
+        self.input_frame
+    }
+}
+
+fn log_invalid_timing(config: &BaseConfig) {
+    error!("");
+    error!("!!  Invalid DMX timing in config: break {}, mark after break {}, rate {}  !!",
+        config.dmx_break_time, config.dmx_mark_after_break_time, config.dmx_output_rate);
+    error!("    dmx_break_time must be {}-{}, dmx_mark_after_break_time {}-{} (in units of 10.67us)",
+        MIN_BREAK_TIME, MAX_BREAK_TIME, MIN_MARK_AFTER_BREAK_TIME, MAX_MARK_AFTER_BREAK_TIME);
+    error!("    and dmx_output_rate 0-{} packets/s with 0 being as fast as possible.", MAX_OUTPUT_RATE);
+    error!("");
+}
+
+pub struct OpenDmx {
+    port: Option<Box<dyn SerialPort>>,
+    port_name: String,
+    universe: u16,
+    last_reconnect_attempt: Option<Instant>,
+}
+
+impl OpenDmx {
+    pub fn new(port_name_input: &str, universe: u16) -> OpenDmx {
+        OpenDmx {
+            port: None,
+            port_name: String::from(port_name_input),
+            universe,
+            last_reconnect_attempt: None,
+        }
+    }
+
+    pub fn open(&mut self) -> Result<(), Error> {
+        if self.port.is_some() {
+            return Ok(());
+        }
+
+        let port = new(&self.port_name, OPEN_DMX_BAUD_RATE)
+            .data_bits(DataBits::Eight)
+            .stop_bits(StopBits::Two)
+            .parity(Parity::None)
+            .flow_control(FlowControl::None)
+            .timeout(Duration::from_millis(100))
+            .open()?;
+
+        self.port = Some(port);
+        Ok(())
+    }
+
+    fn reconnect(&mut self) -> bool {
+        if let Some(last_attempt) = self.last_reconnect_attempt {
+            if last_attempt.elapsed() < RECONNECT_INTERVAL {
+                return false;
+            }
+        }
+        self.last_reconnect_attempt = Some(Instant::now());
+        match self.open() {
+            Ok(()) => {
+                info!("Reconnected Open DMX port: {}", self.port_name);
+                self.last_reconnect_attempt = None;
+                true
+            },
+            Err(error) => {
+                debug!("Reconnecting Open DMX port {} failed: {:?}", self.port_name, error);
+                false
+            }
+        }
+    }
+
+    fn write_packet(&mut self, frame: &[u8]) -> Result<(), Error> {
+        let port = self.port.as_mut().ok_or(Error::PortClosed)?;
+        // A DMX packet starts with a break followed by a mark after break, then the start code and all slots
+        port.set_break()?;
+        sleep(OPEN_DMX_BREAK_TIME);
+        port.clear_break()?;
+        sleep(OPEN_DMX_MARK_AFTER_BREAK_TIME);
+        let mut paket = Vec::with_capacity(MAX_FRAME_SIZE + 1);
+        paket.push(0);
+        paket.extend_from_slice(&frame[0..min(frame.len(), MAX_FRAME_SIZE)]);
+        paket.resize(MAX_FRAME_SIZE + 1, 0);
+        port.write_all(&paket)?;
+        // Wait until all slots left the wire, the break of the next packet would cut them off otherwise
+        port.flush()?;
+        Ok(())
+    }
+
+    pub fn write_frame(&mut self, frame: &[u8]) {
+        if self.port.is_none() && !self.reconnect() {
+            return;
+        }
+        if let Err(error) = self.write_packet(frame) {
+            error!("Frame was not successfully written to Open DMX: {:?}", error);
+            warn!("Closed Open DMX port {}, retrying to open it every {}s", self.port_name, RECONNECT_INTERVAL.as_secs());
+            self.port = None;
+            self.last_reconnect_attempt = Some(Instant::now());
+        }
+    }
+}
+
+impl DmxOutput for OpenDmx {
+    fn write(&mut self, universes: &DmxUniverses) {
+        self.write_frame(frame_for(universes, self.universe));
+    }
+}
+
 pub fn open_dmxis_port(config: &BaseConfig) -> Result<Dmxis, Box<dyn StdError>> {
     let serial_port = if cfg!(windows) {
         &config.dmx_serial_port_win
@@ -156,19 +615,31 @@ pub fn open_dmxis_port(config: &BaseConfig) -> Result<Dmxis, Box<dyn StdError>>
     } else {
         &config.dmx_serial_port_other
     };
-    let mut dmxis = Dmxis::new(serial_port);
+    let usb_filter = usb_filter_from_config(config)?;
+    let mut dmxis = Dmxis::new(serial_port, usb_filter, config.enttec_universe, config.dmx_verify_widget);
+    if dmxis.set_timing(config.dmx_break_time, config.dmx_mark_after_break_time, config.dmx_output_rate).is_err() {
+        log_invalid_timing(config);
+        return Err("".into());
+    }
+    dmxis.set_input(config.dmx_input)?;
+    dmxis.set_second_port(second_port_from_config(config)?);
     let opened = dmxis.open();
     if opened.is_ok() {
-        info!("Opened DMX serial port:  {}", serial_port);
+        info!("Opened DMX serial port:  {}", dmxis.port_name);
         return Ok(dmxis);
     }
     error!("");
     error!("!!  No dmx port to open, check your config and that the dmx interface is properly connected.  !!");
+    if let Err(Error::NoReply) = opened {
+        error!("    {} didn't answer like an Enttec Pro compatible device.", dmxis.port_name);
+        error!("    Set dmx_verify_widget to false in your config to use it anyway.");
+    }
     if let Ok(ports) = available_ports() {
         error!("    Available serial ports are:");
         for p in ports.iter() {
             if let SerialPortType::UsbPort(usb_port_info) = &p.port_type {
-                error!("    - {}: {:?}", p.port_name, usb_port_info.product);
+                error!("    - {}: {:?} (vid {:04x}, pid {:04x}, serial number {:?})",
+                    p.port_name, usb_port_info.product, usb_port_info.vid, usb_port_info.pid, usb_port_info.serial_number);
             }
         }
     } else {
@@ -176,4 +647,229 @@ pub fn open_dmxis_port(config: &BaseConfig) -> Result<Dmxis, Box<dyn StdError>>
     }
     error!("");
     return Err("".into());
-}
\ No newline at end of file
+}
+
+fn usb_filter_from_config(config: &BaseConfig) -> Result<Option<UsbFilter>, Box<dyn StdError>> {
+    if config.dmx_usb_vid.is_empty() && config.dmx_usb_pid.is_empty() && config.dmx_usb_serial.is_empty() {
+        return Ok(None);
+    }
+    let vendor_id = parse_usb_id(&config.dmx_usb_vid, "dmx_usb_vid")?;
+    let product_id = parse_usb_id(&config.dmx_usb_pid, "dmx_usb_pid")?;
+    let serial_number = if config.dmx_usb_serial.is_empty() { None } else { Some(config.dmx_usb_serial.clone()) };
+    Ok(Some(UsbFilter { vendor_id, product_id, serial_number }))
+}
+
+fn second_port_from_config(config: &BaseConfig) -> Result<Option<SecondPort>, Box<dyn StdError>> {
+    if config.enttec_universe_2 == 0 {
+        return Ok(None);
+    }
+    let hex = config.dmx_mk2_api_key.trim_start_matches("0x").trim_start_matches("0X");
+    let api_key = u32::from_str_radix(hex, 16).ok();
+    let labels_set = config.dmx_mk2_api_key_label != 0
+        && config.dmx_mk2_port_assignment_label != 0
+        && config.dmx_mk2_send_dmx_2_label != 0;
+    if api_key.is_none() || !labels_set {
+        error!("");
+        error!("!!  enttec_universe_2 needs dmx_mk2_api_key, dmx_mk2_api_key_label, dmx_mk2_port_assignment_label  !!");
+        error!("!!  and dmx_mk2_send_dmx_2_label to be set. Enttec provides them with the DMX USB Pro Mk2 API.  !!");
+        error!("");
+        return Err("".into());
+    }
+    info!("DMX second port:         universe {}", config.enttec_universe_2);
+    Ok(Some(SecondPort {
+        universe: config.enttec_universe_2,
+        api_key: api_key.unwrap().to_le_bytes(),
+        api_key_command: config.dmx_mk2_api_key_label,
+        port_assignment_command: config.dmx_mk2_port_assignment_label,
+        send_packet_command: config.dmx_mk2_send_dmx_2_label,
+    }))
+}
+
+// USB ids are written in hex like in device listings, e.g. '0403' or '0x0403'
+fn parse_usb_id(id: &str, field: &str) -> Result<Option<u16>, Box<dyn StdError>> {
+    if id.is_empty() {
+        return Ok(None);
+    }
+    let hex = id.trim_start_matches("0x").trim_start_matches("0X");
+    match u16::from_str_radix(hex, 16) {
+        Ok(value) => Ok(Some(value)),
+        Err(_) => {
+            error!("");
+            error!("!!  Invalid {} '{}' in config, expected a hex value like '0403'.  !!", field, id);
+            error!("");
+            Err("".into())
+        }
+    }
+}
+
+pub fn open_open_dmx_port(config: &BaseConfig) -> Result<OpenDmx, Box<dyn StdError>> {
+    let mut open_dmx = OpenDmx::new(&config.open_dmx_serial_port, config.open_dmx_universe);
+    let opened = open_dmx.open();
+    if opened.is_ok() {
+        info!("Opened Open DMX port:    {}", config.open_dmx_serial_port);
+        return Ok(open_dmx);
+    }
+    error!("");
+    error!("!!  Couldn't open Open DMX port '{}': {:?}  !!", config.open_dmx_serial_port, opened.unwrap_err());
+    error!("    Check open_dmx_serial_port in your config and that the dongle is properly connected.");
+    error!("");
+    Err("".into())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use serialport::TTYPort;
+    use std::io::{Read, Write};
+
+    fn packet(command: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![START_VAL, command, payload.len() as u8, (payload.len() >> 8) as u8];
+        packet.extend_from_slice(payload);
+        packet.push(END_VAL);
+        packet
+    }
+
+    fn received_dmx_packet(slots: &[u8]) -> Vec<u8> {
+        let mut payload = vec![0, 0];
+        payload.extend_from_slice(slots);
+        packet(RECEIVED_DMX_COMMAND, &payload)
+    }
+
+    // The other side of a pseudo terminal plays the widget
+    fn connected_dmxis() -> (Dmxis, TTYPort) {
+        let (host, widget) = TTYPort::pair().unwrap();
+        let mut dmxis = Dmxis::new("", None, 0, false);
+        dmxis.port = Some(Box::new(host));
+        (dmxis, widget)
+    }
+
+    fn send(widget: &mut TTYPort, packet: &[u8]) {
+        widget.write_all(packet).unwrap();
+        widget.flush().unwrap();
+        sleep(Duration::from_millis(20));
+    }
+
+    fn read_request(widget: &mut TTYPort) -> Vec<u8> {
+        sleep(Duration::from_millis(20));
+        let mut request = vec![0; widget.bytes_to_read().unwrap() as usize];
+        widget.read_exact(&mut request).unwrap();
+        request
+    }
+
+    fn usb_port_info(vid: u16, pid: u16, serial_number: &str) -> UsbPortInfo {
+        UsbPortInfo { vid, pid, serial_number: Some(String::from(serial_number)), manufacturer: None, product: None }
+    }
+
+    #[test]
+    fn usb_ids_are_hex_with_or_without_prefix() {
+        assert_eq!(parse_usb_id("0403", "dmx_usb_vid").unwrap(), Some(0x0403));
+        assert_eq!(parse_usb_id("0x6001", "dmx_usb_pid").unwrap(), Some(0x6001));
+        assert_eq!(parse_usb_id("0XABCD", "dmx_usb_pid").unwrap(), Some(0xABCD));
+        assert_eq!(parse_usb_id("", "dmx_usb_vid").unwrap(), None);
+        assert!(parse_usb_id("ftdi", "dmx_usb_vid").is_err());
+        assert!(parse_usb_id("12345", "dmx_usb_vid").is_err());
+    }
+
+    #[test]
+    fn usb_filters_match_on_the_given_descriptors_only() {
+        let widget = usb_port_info(0x0403, 0x6001, "EN123456");
+        let other = usb_port_info(0x1a86, 0x7523, "0001");
+        let vid_only = UsbFilter { vendor_id: Some(0x0403), product_id: None, serial_number: None };
+        assert!(vid_only.matches(&widget));
+        assert!(!vid_only.matches(&other));
+        let pid_only = UsbFilter { vendor_id: None, product_id: Some(0x6001), serial_number: None };
+        assert!(pid_only.matches(&widget));
+        assert!(!pid_only.matches(&other));
+        let serial_only = UsbFilter { vendor_id: None, product_id: None, serial_number: Some(String::from("EN123456")) };
+        assert!(serial_only.matches(&widget));
+        assert!(!serial_only.matches(&other));
+        assert!(!serial_only.matches(&UsbPortInfo { serial_number: None, ..widget.clone() }));
+        let all = UsbFilter { vendor_id: Some(0x0403), product_id: Some(0x6001), serial_number: Some(String::from("EN000000")) };
+        assert!(!all.matches(&widget));
+    }
+
+    #[test]
+    fn reads_the_widget_parameters() {
+        let (mut dmxis, mut widget) = connected_dmxis();
+        send(&mut widget, &packet(GET_PARAMETERS_COMMAND, &[4, 1, 9, 1, 40, 0, 0]));
+        let parameters = dmxis.get_widget_params().unwrap();
+        assert_eq!(read_request(&mut widget), packet(GET_PARAMETERS_COMMAND, &[0, 0]));
+        assert_eq!(parameters.firmware_version, (1, 4));
+        assert_eq!(parameters.break_time, 9);
+        assert_eq!(parameters.mark_after_break_time, 1);
+        assert_eq!(parameters.output_rate, 40);
+    }
+
+    #[test]
+    fn reads_the_bcd_serial_number() {
+        let (mut dmxis, mut widget) = connected_dmxis();
+        // Noise and other replies before the answer are skipped
+        let mut replies = vec![0x00, 0x12];
+        replies.extend(packet(RECEIVED_DMX_COMMAND, &[0, 0, 1]));
+        replies.extend(packet(GET_SERIAL_NUMBER_COMMAND, &[0x78, 0x56, 0x34, 0x12]));
+        send(&mut widget, &replies);
+        assert_eq!(dmxis.get_serial_number().unwrap(), "12345678");
+        assert_eq!(read_request(&mut widget), packet(GET_SERIAL_NUMBER_COMMAND, &[]));
+    }
+
+    #[test]
+    fn short_or_missing_replies_are_no_reply() {
+        let (mut dmxis, mut widget) = connected_dmxis();
+        send(&mut widget, &packet(GET_PARAMETERS_COMMAND, &[4, 1]));
+        assert!(matches!(dmxis.get_widget_params(), Err(Error::NoReply)));
+        send(&mut widget, &packet(GET_SERIAL_NUMBER_COMMAND, &[0x78, 0x56, 0x34]));
+        assert!(matches!(dmxis.get_serial_number(), Err(Error::NoReply)));
+        // A reply with another label doesn't answer the request
+        send(&mut widget, &packet(GET_PARAMETERS_COMMAND, &[4, 1, 9, 1, 40]));
+        assert!(matches!(dmxis.get_serial_number(), Err(Error::NoReply)));
+    }
+
+    #[test]
+    fn timing_accepts_the_api_ranges_only() {
+        let mut dmxis = Dmxis::new("", None, 0, false);
+        for (break_time, mark_after_break_time, output_rate) in [(9, 1, 0), (127, 127, 40), (20, 5, 30)] {
+            assert!(dmxis.set_timing(break_time, mark_after_break_time, output_rate).is_ok());
+            assert_eq!((dmxis.break_time, dmxis.mark_after_break_time, dmxis.output_rate), (break_time, mark_after_break_time, output_rate));
+        }
+        for (break_time, mark_after_break_time, output_rate) in [(8, 5, 30), (128, 5, 30), (20, 0, 30), (20, 128, 30), (20, 5, 41)] {
+            assert!(matches!(dmxis.set_timing(break_time, mark_after_break_time, output_rate), Err(Error::InvalidParameters)));
+            assert_eq!((dmxis.break_time, dmxis.mark_after_break_time, dmxis.output_rate), (20, 5, 30));
+        }
+    }
+
+    #[test]
+    fn changed_timing_is_sent_to_an_open_widget() {
+        let (mut dmxis, mut widget) = connected_dmxis();
+        dmxis.set_timing(20, 5, 30).unwrap();
+        assert_eq!(read_request(&mut widget), packet(SET_PARAMETERS_COMMAND, &[0, 0, 20, 5, 30]));
+        dmxis.set_timing(20, 5, 30).unwrap();
+        assert!(read_request(&mut widget).is_empty());
+    }
+
+    #[test]
+    fn delivers_received_dmx_while_enabled() {
+        let (mut dmxis, mut widget) = connected_dmxis();
+        dmxis.set_input(true).unwrap();
+        send(&mut widget, &received_dmx_packet(&[10, 20, 30]));
+        let frame = dmxis.read_input().unwrap();
+        assert_eq!(frame[..4], [10, 20, 30, 0]);
+    }
+
+    #[test]
+    fn disabled_input_drains_and_discards_received_dmx() {
+        let (mut dmxis, mut widget) = connected_dmxis();
+        dmxis.set_input(true).unwrap();
+        send(&mut widget, &received_dmx_packet(&[10, 20, 30]));
+        assert!(dmxis.read_input().is_some());
+
+        dmxis.set_input(false).unwrap();
+        send(&mut widget, &received_dmx_packet(&[40, 50, 60]));
+        assert!(dmxis.read_input().is_none());
+        assert!(dmxis.read_buffer.is_empty());
+        assert_eq!(dmxis.port.as_ref().unwrap().bytes_to_read().unwrap(), 0);
+
+        // Nothing received while disabled shows up after enabling again
+        dmxis.set_input(true).unwrap();
+        assert!(dmxis.read_input().is_none());
+    }
+}
@@ -8,18 +8,22 @@ use log::{debug, trace};
 
 pub struct Fader {
     fader_type: FaderType,
+    universe: u16,
     channel: usize,
     value: u8,
     current_value: u8,
     movement: Option<Movement>,
     midi_params: Option<MidiParams>,
-    timeout_start: Option<Instant>,
+    envelope: Option<Envelope>,
+    cc_params: Option<CcParams>,
+    smoothed_value: Option<(f64, Instant)>,
 }
 
 #[derive(Debug)]
 pub enum FaderType {
     Default,
     Midi,
+    Cc,
 }
 
 impl fmt::Display for FaderType {
@@ -59,6 +63,40 @@ impl fmt::Display for Shape {
 struct MidiParams {
     note: u8,
     timeout: u64,
+    velocity: bool,
+    attack_ms: u64,
+    decay_ms: u64,
+    sustain: f64,
+    release_ms: u64,
+}
+
+// A note hit with its velocity, released on note off or timeout
+struct Envelope {
+    start: Instant,
+    velocity: u8,
+    release: Option<(Instant, f64)>,
+}
+
+struct CcParams {
+    cc: u8,
+    min: u8,
+    max: u8,
+    curve: Curve,
+    smoothing_ms: u64,
+}
+
+// Response of a CC fader, exponential gives more control over low values and logarithmic over high values
+#[derive(Debug)]
+pub enum Curve {
+    Linear,
+    Exponential,
+    Logarithmic,
+}
+
+impl fmt::Display for Curve {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
 }
 
 impl Fader {
@@ -71,10 +109,11 @@ impl Fader {
                 }
                 self.current_value
             },
+            FaderType::Cc => self.current_value,
         }
     }
 
-    pub fn update_state(&mut self, selected_tempo: u8, start_time: Instant, notes: [Option<u8>; 128], config: &BaseConfig) {
+    pub fn update_state(&mut self, selected_tempo: f64, start_time: Instant, notes: [Option<u8>; 128], note_hits: [Option<u8>; 128], controls: [Option<u8>; 128], config: &BaseConfig) {
         match &self.fader_type {
             FaderType::Default => {
                 if let Some(movement) = &self.movement {
@@ -85,28 +124,57 @@ impl Fader {
             },
             FaderType::Midi => {
                 if let Some(midi_params) = &self.midi_params {
-                    if let Some(note_velocity) = notes[midi_params.note as usize] {
-                        if note_velocity > 0 {
-                            self.timeout_start = Some(Instant::now());
-                            self.current_value = self.value;
-                        } else {
-                            trace!("Stopped due to note off");
-                            self.current_value = 0;
-                        }
+                    let now = Instant::now();
+                    // Every hit restarts the envelope, even if the note wasn't released in between
+                    if let Some(velocity) = note_hits[midi_params.note as usize] {
+                        self.envelope = Some(Envelope { start: now, velocity, release: None });
                     }
-                    if let Some(timeout_start) = &self.timeout_start {
-                        if timeout_start.elapsed().as_millis() as u64 >= midi_params.timeout {
-                            trace!("Reached time out after {}ms", midi_params.timeout);
-                            self.current_value = 0;
-                            self.timeout_start = None;
+                    if let Some(envelope) = &mut self.envelope {
+                        // A note off is only honoured after the attack, so short notes still reach their peak.
+                        // The note stays off in the notes until the next note on, so the release follows later.
+                        let attacked = now.saturating_duration_since(envelope.start).as_millis() as u64 >= midi_params.attack_ms;
+                        if envelope.release.is_none() {
+                            if notes[midi_params.note as usize] == Some(0) && attacked {
+                                trace!("Released due to note off");
+                                envelope.release = Some((now, envelope_level(midi_params, envelope, now)));
+                            } else if now.saturating_duration_since(envelope.start).as_millis() as u64 >= midi_params.timeout {
+                                trace!("Reached time out after {}ms", midi_params.timeout);
+                                envelope.release = Some((now, envelope_level(midi_params, envelope, now)));
+                            }
                         }
                     }
+                    let level = self.envelope.as_ref().map_or(0.0, |envelope| envelope_level(midi_params, envelope, now));
+                    if level <= 0.0 && self.envelope.as_ref().is_some_and(|envelope| envelope.release.is_some()) {
+                        self.envelope = None;
+                    }
+                    let velocity_scale = match &self.envelope {
+                        Some(envelope) if midi_params.velocity => envelope.velocity.min(127) as f64 / 127.0,
+                        _ => 1.0,
+                    };
+                    self.current_value = (self.value as f64 * level * velocity_scale).round().clamp(0.0, 255.0) as u8;
                     if !config.midi_faders {
                         self.current_value = 0;
                     }
                 } else {
                     self.current_value = 0;
                 }
+            },
+            FaderType::Cc => {
+                if let Some(cc_params) = &self.cc_params {
+                    let target = calculate_cc_value(cc_params, controls[cc_params.cc as usize]);
+                    let now = Instant::now();
+                    let smoothed = match self.smoothed_value {
+                        Some((previous, previous_time)) => {
+                            let elapsed_ms = now.saturating_duration_since(previous_time).as_secs_f64() * 1000.0;
+                            smooth_cc_value(cc_params, previous, target, elapsed_ms)
+                        },
+                        None => target,
+                    };
+                    self.smoothed_value = Some((smoothed, now));
+                    self.current_value = smoothed.round().clamp(0.0, 255.0) as u8;
+                } else {
+                    self.current_value = 0;
+                }
             }
         }
     }
@@ -114,21 +182,27 @@ impl Fader {
     pub fn get_channel(&self) -> usize {
         self.channel
     }
-}
 
-pub fn fader_from_mapping(channel: &Value, properties: &Value) -> Option<Fader> {
-    if !channel.is_number() {
-        return None;
+    pub fn get_universe(&self) -> u16 {
+        self.universe
     }
+}
+
+pub fn fader_from_mapping(address: &Value, properties: &Value) -> Option<Fader> {
+    let (universe, channel) = parse_address(address)?;
     let mut fader = Fader{
         fader_type: FaderType::Default,
-        channel: channel.as_u64().unwrap() as usize,
+        universe,
+        channel,
         value: 0,
         current_value: 0,
         movement: None,
         midi_params: None,
-        timeout_start: None,
+        envelope: None,
+        cc_params: None,
+        smoothed_value: None,
     };
+    let mut params = None;
     if let Some(props) = properties.as_mapping() {
         for (key, value) in props.iter() {
             if key.is_string() && key.eq("value") && value.is_number() {
@@ -138,18 +212,36 @@ pub fn fader_from_mapping(channel: &Value, properties: &Value) -> Option<Fader>
                 match fader_type {
                     "default" => fader.fader_type = FaderType::Default,
                     "midi" => fader.fader_type = FaderType::Midi,
+                    "cc" => fader.fader_type = FaderType::Cc,
                     _ => fader.fader_type = FaderType::Default,
                 }
             } else if key.is_string() && key.eq("movement") && value.is_mapping() {
                 fader.movement = Some(movement_from_mapping(value.as_mapping().unwrap()));
             } else if key.is_string() && key.eq("params") && value.is_mapping() {
-                fader.midi_params = Some(midi_params_from_mapping(value.as_mapping().unwrap()));
+                params = value.as_mapping();
             }
         }
     }
+    // The type may follow the params in the scene file, so they are parsed once all properties are known
+    if let Some(params) = params {
+        match fader.fader_type {
+            FaderType::Midi => fader.midi_params = Some(midi_params_from_mapping(params)),
+            FaderType::Cc => fader.cc_params = Some(cc_params_from_mapping(params)),
+            FaderType::Default => (),
+        }
+    }
     return Some(fader)
 }
 
+// Faders are addressed either by channel only, using the first universe, or as 'universe:channel'
+pub fn parse_address(address: &Value) -> Option<(u16, usize)> {
+    if address.is_u64() {
+        return Some((1, address.as_u64().unwrap() as usize));
+    }
+    let (universe, channel) = address.as_str()?.split_once(':')?;
+    Some((universe.trim().parse().ok()?, channel.trim().parse().ok()?))
+}
+
 fn movement_from_mapping(movement_input: &Mapping) -> Movement {
     let mut movement = Movement {
         delay_percentage: None,
@@ -228,24 +320,109 @@ fn movement_from_mapping(movement_input: &Mapping) -> Movement {
 fn midi_params_from_mapping(midi_params_input: &Mapping) -> MidiParams {
     let mut midi_params = MidiParams {
         note: 0,
-        timeout: 2000 // Default timeout of 2 seconds
+        timeout: 2000, // Default timeout of 2 seconds
+        velocity: false,
+        attack_ms: 0,
+        decay_ms: 0,
+        sustain: 1.0,
+        release_ms: 0,
     };
     for (key, value) in midi_params_input.iter() {
         if key.is_string() && key.as_str().unwrap().eq("note") && value.is_number() {
-            midi_params.note = value.as_u64().unwrap() as u8;
+            midi_params.note = value.as_u64().unwrap().min(127) as u8;
         } else if key.is_string() && key.as_str().unwrap().eq("timeout_ms") && value.is_number() {
             midi_params.timeout = value.as_u64().unwrap();
+        } else if key.is_string() && key.as_str().unwrap().eq("velocity") && value.is_bool() {
+            midi_params.velocity = value.as_bool().unwrap();
+        } else if key.is_string() && key.as_str().unwrap().eq("attack_ms") && value.is_number() {
+            midi_params.attack_ms = value.as_u64().unwrap();
+        } else if key.is_string() && key.as_str().unwrap().eq("decay_ms") && value.is_number() {
+            midi_params.decay_ms = value.as_u64().unwrap();
+        } else if key.is_string() && key.as_str().unwrap().eq("sustain_percentage") && value.is_number() {
+            midi_params.sustain = (value.as_f64().unwrap() / 100.0).clamp(0.0, 1.0);
+        } else if key.is_string() && key.as_str().unwrap().eq("release_ms") && value.is_number() {
+            midi_params.release_ms = value.as_u64().unwrap();
         }
     }
     midi_params
 }
 
-fn calculate_movement(movement: &Movement, beats_per_minute: u8, start_time: Instant) -> u8 {
+// Level of 0-1 following attack, decay and sustain while the note is held and release afterwards
+fn envelope_level(midi_params: &MidiParams, envelope: &Envelope, now: Instant) -> f64 {
+    if let Some((release_start, release_level)) = envelope.release {
+        let released_ms = now.saturating_duration_since(release_start).as_secs_f64() * 1000.0;
+        if released_ms >= midi_params.release_ms as f64 {
+            return 0.0;
+        }
+        return release_level * (1.0 - released_ms / midi_params.release_ms as f64);
+    }
+    let held_ms = now.saturating_duration_since(envelope.start).as_secs_f64() * 1000.0;
+    let attack_ms = midi_params.attack_ms as f64;
+    let decay_ms = midi_params.decay_ms as f64;
+    if held_ms < attack_ms {
+        held_ms / attack_ms
+    } else if held_ms < attack_ms + decay_ms {
+        1.0 - (1.0 - midi_params.sustain) * (held_ms - attack_ms) / decay_ms
+    } else {
+        midi_params.sustain
+    }
+}
+
+fn cc_params_from_mapping(cc_params_input: &Mapping) -> CcParams {
+    let mut cc_params = CcParams {
+        cc: 0,
+        min: 0,
+        max: 255,
+        curve: Curve::Linear,
+        smoothing_ms: 0,
+    };
+    for (key, value) in cc_params_input.iter() {
+        if key.is_string() && key.as_str().unwrap().eq("cc") && value.is_number() {
+            cc_params.cc = value.as_u64().unwrap().min(127) as u8;
+        } else if key.is_string() && key.as_str().unwrap().eq("min") && value.is_number() {
+            cc_params.min = value.as_u64().unwrap() as u8;
+        } else if key.is_string() && key.as_str().unwrap().eq("max") && value.is_number() {
+            cc_params.max = value.as_u64().unwrap() as u8;
+        } else if key.is_string() && key.as_str().unwrap().eq("curve") && value.is_string() {
+            let curve = value.as_str().unwrap();
+            match curve {
+                "linear" => cc_params.curve = Curve::Linear,
+                "exponential" => cc_params.curve = Curve::Exponential,
+                "logarithmic" => cc_params.curve = Curve::Logarithmic,
+                _ => cc_params.curve = Curve::Linear,
+            }
+        } else if key.is_string() && key.as_str().unwrap().eq("smoothing_ms") && value.is_number() {
+            cc_params.smoothing_ms = value.as_u64().unwrap();
+        }
+    }
+    cc_params
+}
+
+// Scales the control value of 0-127 to min-max, faders stay at min until the control is moved
+fn calculate_cc_value(cc_params: &CcParams, control: Option<u8>) -> f64 {
+    let position = control.unwrap_or(0).min(127) as f64 / 127.0;
+    let curved_position = match cc_params.curve {
+        Curve::Linear => position,
+        Curve::Exponential => position * position,
+        Curve::Logarithmic => position.sqrt(),
+    };
+    cc_params.min as f64 + (cc_params.max as f64 - cc_params.min as f64) * curved_position
+}
+
+// Smoothing follows the control exponentially, after smoothing_ms about two thirds of a change are reached
+fn smooth_cc_value(cc_params: &CcParams, previous: f64, target: f64, elapsed_ms: f64) -> f64 {
+    if cc_params.smoothing_ms == 0 {
+        return target;
+    }
+    previous + (target - previous) * (1.0 - (-elapsed_ms / cc_params.smoothing_ms as f64).exp())
+}
+
+fn calculate_movement(movement: &Movement, beats_per_minute: f64, start_time: Instant) -> u8 {
     let max = movement.max as f64;
     let min = movement.min as f64;
     let curve_max = movement.curve_max.unwrap() as f64;
     let curve_min = movement.curve_min.unwrap() as f64;
-    let beat_duration_ms: f64 = 60000.0 / beats_per_minute as f64;
+    let beat_duration_ms: f64 = 60000.0 / beats_per_minute;
     let movement_duration_ms: f64 = if let Some(percentage) = movement.duration_percentage {
         beat_duration_ms * (percentage as f64 / 100.0)
     } else if let Some(ms) = movement.duration_ms {
@@ -344,3 +521,129 @@ fn triangle_function(mut position: f64, reverse: bool) -> f64 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn midi_params(attack_ms: u64, decay_ms: u64, sustain: f64, release_ms: u64) -> MidiParams {
+        MidiParams { note: 60, timeout: 2000, velocity: false, attack_ms, decay_ms, sustain, release_ms }
+    }
+
+    fn level_at(midi_params: &MidiParams, envelope: &Envelope, start: Instant, ms: u64) -> f64 {
+        envelope_level(midi_params, envelope, start + Duration::from_millis(ms))
+    }
+
+    fn cc_params(curve: Curve, smoothing_ms: u64) -> CcParams {
+        CcParams { cc: 1, min: 0, max: 255, curve, smoothing_ms }
+    }
+
+    #[test]
+    fn cc_curves_share_their_endpoints() {
+        for curve in [Curve::Linear, Curve::Exponential, Curve::Logarithmic] {
+            let params = cc_params(curve, 0);
+            assert_eq!(calculate_cc_value(&params, None), 0.0);
+            assert_eq!(calculate_cc_value(&params, Some(0)), 0.0);
+            assert_eq!(calculate_cc_value(&params, Some(127)), 255.0);
+        }
+    }
+
+    #[test]
+    fn cc_curves_bend_the_midpoint() {
+        let midpoint = 127.0 / 2.0;
+        let value_at_midpoint = |curve| calculate_cc_value(&cc_params(curve, 0), Some(64)) / 255.0 * 127.0;
+        assert!((value_at_midpoint(Curve::Linear) - 64.0).abs() < 1e-9);
+        assert!((value_at_midpoint(Curve::Exponential) - 64.0 * 64.0 / 127.0).abs() < 1e-9);
+        assert!((value_at_midpoint(Curve::Logarithmic) - (64.0f64 * 127.0).sqrt()).abs() < 1e-9);
+        assert!(value_at_midpoint(Curve::Exponential) < midpoint);
+        assert!(value_at_midpoint(Curve::Logarithmic) > midpoint);
+    }
+
+    #[test]
+    fn cc_range_is_scaled_between_min_and_max() {
+        let params = CcParams { cc: 1, min: 200, max: 100, curve: Curve::Linear, smoothing_ms: 0 };
+        assert_eq!(calculate_cc_value(&params, Some(0)), 200.0);
+        assert_eq!(calculate_cc_value(&params, Some(127)), 100.0);
+        assert_eq!(calculate_cc_value(&params, Some(255)), 100.0);
+    }
+
+    #[test]
+    fn cc_smoothing_converges_on_the_target() {
+        let params = cc_params(Curve::Linear, 100);
+        assert_eq!(smooth_cc_value(&params, 0.0, 255.0, 0.0), 0.0);
+        assert!((smooth_cc_value(&params, 0.0, 255.0, 100.0) - 255.0 * (1.0 - (-1.0f64).exp())).abs() < 1e-9);
+        let mut value = 0.0;
+        for _ in 0..100 {
+            let next = smooth_cc_value(&params, value, 255.0, 20.0);
+            assert!(next > value && next < 255.0);
+            value = next;
+        }
+        assert!((value - 255.0).abs() < 0.5);
+        assert_eq!(smooth_cc_value(&cc_params(Curve::Linear, 0), 0.0, 255.0, 0.0), 255.0);
+    }
+
+    #[test]
+    fn envelope_follows_attack_decay_and_sustain() {
+        let params = midi_params(100, 100, 0.5, 100);
+        let start = Instant::now();
+        let envelope = Envelope { start, velocity: 127, release: None };
+        assert_eq!(level_at(&params, &envelope, start, 0), 0.0);
+        assert!((level_at(&params, &envelope, start, 50) - 0.5).abs() < 1e-9);
+        assert_eq!(level_at(&params, &envelope, start, 100), 1.0);
+        assert!((level_at(&params, &envelope, start, 150) - 0.75).abs() < 1e-9);
+        assert_eq!(level_at(&params, &envelope, start, 200), 0.5);
+        assert_eq!(level_at(&params, &envelope, start, 5000), 0.5);
+    }
+
+    #[test]
+    fn envelope_without_attack_and_decay_jumps_to_sustain() {
+        let start = Instant::now();
+        let envelope = Envelope { start, velocity: 127, release: None };
+        assert_eq!(level_at(&midi_params(0, 100, 0.5, 0), &envelope, start, 0), 1.0);
+        assert_eq!(level_at(&midi_params(0, 0, 0.5, 0), &envelope, start, 0), 0.5);
+        assert_eq!(level_at(&midi_params(100, 0, 0.5, 0), &envelope, start, 100), 0.5);
+    }
+
+    #[test]
+    fn envelope_releases_from_the_level_at_note_off() {
+        let start = Instant::now();
+        let envelope = Envelope { start, velocity: 127, release: Some((start, 0.8)) };
+        let params = midi_params(0, 0, 1.0, 100);
+        assert!((level_at(&params, &envelope, start, 0) - 0.8).abs() < 1e-9);
+        assert!((level_at(&params, &envelope, start, 50) - 0.4).abs() < 1e-9);
+        assert_eq!(level_at(&params, &envelope, start, 100), 0.0);
+        assert_eq!(level_at(&midi_params(0, 0, 1.0, 0), &envelope, start, 0), 0.0);
+    }
+
+    #[test]
+    fn params_are_parsed_for_the_fader_type_only() {
+        let properties: Value = serde_yaml::from_str("{params: {cc: 7, note: 60}, type: cc}").unwrap();
+        let fader = fader_from_mapping(&Value::from("2:17"), &properties).unwrap();
+        assert_eq!(fader.cc_params.as_ref().unwrap().cc, 7);
+        assert!(fader.midi_params.is_none());
+
+        let properties: Value = serde_yaml::from_str("{params: {cc: 7, note: 60}, type: midi}").unwrap();
+        let fader = fader_from_mapping(&Value::from(1), &properties).unwrap();
+        assert_eq!(fader.midi_params.as_ref().unwrap().note, 60);
+        assert!(fader.cc_params.is_none());
+    }
+
+    #[test]
+    fn note_off_in_the_same_frame_waits_for_the_attack() {
+        let properties: Value = serde_yaml::from_str("{value: 200, type: midi, params: {note: 60, attack_ms: 20, release_ms: 100}}").unwrap();
+        let mut fader = fader_from_mapping(&Value::from(1), &properties).unwrap();
+        let config = BaseConfig::default();
+        let mut notes = [None; 128];
+        let mut note_hits = [None; 128];
+        notes[60] = Some(0);
+        note_hits[60] = Some(127);
+        fader.update_state(120.0, Instant::now(), notes, note_hits, [None; 128], &config);
+        assert!(fader.envelope.as_ref().unwrap().release.is_none());
+
+        std::thread::sleep(Duration::from_millis(25));
+        fader.update_state(120.0, Instant::now(), notes, [None; 128], [None; 128], &config);
+        let (_, release_level) = fader.envelope.as_ref().unwrap().release.unwrap();
+        assert_eq!(release_level, 1.0);
+    }
+}
@@ -0,0 +1,93 @@
+use std::error::Error as StdError;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::collections::BTreeMap;
+use std::time::Instant;
+use log::{info, error};
+use crate::configuration::BaseConfig;
+use crate::dmx_outputs::{DmxOutput, frame_for};
+use crate::shows::{DmxUniverses, UNIVERSE_SIZE};
+
+// Records the rendered universes to a text file. Each line contains the milliseconds since the
+// recording started, the universe and all 512 channel values in hex. Only changes are recorded.
+pub struct FileRecorder {
+    writer: Option<BufWriter<File>>,
+    path: String,
+    universes: Vec<u16>,
+    start_time: Instant,
+    last_frames: BTreeMap<u16, [u8; UNIVERSE_SIZE]>,
+}
+
+impl FileRecorder {
+    pub fn new(path_input: &str, universes: Vec<u16>) -> FileRecorder {
+        FileRecorder {
+            writer: None,
+            path: String::from(path_input),
+            universes,
+            start_time: Instant::now(),
+            last_frames: BTreeMap::new(),
+        }
+    }
+
+    pub fn open(&mut self) -> Result<(), std::io::Error> {
+        if self.writer.is_some() {
+            return Ok(());
+        }
+        self.writer = Some(BufWriter::new(File::create(&self.path)?));
+        self.start_time = Instant::now();
+        Ok(())
+    }
+
+    fn write_line(&mut self, universe: u16, frame: &[u8]) -> Result<(), std::io::Error> {
+        let elapsed = self.start_time.elapsed().as_millis();
+        if let Some(writer) = self.writer.as_mut() {
+            let values: String = frame.iter().map(|value| format!("{:02x}", value)).collect();
+            writeln!(writer, "{} {} {}", elapsed, universe, values)?;
+        }
+        Ok(())
+    }
+}
+
+impl DmxOutput for FileRecorder {
+    fn write(&mut self, universes: &DmxUniverses) {
+        if self.writer.is_none() {
+            return;
+        }
+        for universe in self.universes.clone() {
+            let frame = frame_for(universes, universe);
+            if self.last_frames.get(&universe).is_some_and(|last_frame| last_frame[..] == frame[..]) {
+                continue;
+            }
+            let mut last_frame = [0; UNIVERSE_SIZE];
+            last_frame.copy_from_slice(frame);
+            self.last_frames.insert(universe, last_frame);
+            if let Err(error) = self.write_line(universe, frame) {
+                error!("Recording to {} failed, stopping the recorder: {:?}", self.path, error);
+                self.writer = None;
+                return;
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        if let Some(writer) = self.writer.as_mut() {
+            if let Err(error) = writer.flush() {
+                error!("Recording to {} couldn't be completed: {:?}", self.path, error);
+            }
+        }
+        self.writer = None;
+    }
+}
+
+pub fn open_file_recorder(config: &BaseConfig) -> Result<FileRecorder, Box<dyn StdError>> {
+    let mut recorder = FileRecorder::new(&config.recorder_path, config.recorder_universes.clone());
+    let opened = recorder.open();
+    if opened.is_ok() {
+        info!("Recording DMX to:        {}", config.recorder_path);
+        return Ok(recorder);
+    }
+    error!("");
+    error!("!!  Couldn't create recording file '{}': {:?}  !!", config.recorder_path, opened.unwrap_err());
+    error!("");
+    Err("".into())
+}
@@ -1,23 +1,36 @@
+mod artnet_devices;
 mod configuration;
+mod dmx_outputs;
 mod enttec_devices;
 mod faders;
+mod file_recorder;
+mod midi_feedback;
+mod midi_learn;
+mod midi_mappings;
 mod midi_ports;
+mod mtc;
+mod sacn_devices;
 mod shows;
+mod tempo;
+mod virtual_devices;
 
 use configuration::BaseConfig;
-use enttec_devices::Dmxis;
+use dmx_outputs::DmxOutput;
 use shows::Show;
 use midi_ports::MidiPort;
+use midi_feedback::MidiFeedback;
 
 use core::time::Duration;
 use std::thread::sleep;
 use std::time::Instant;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use log::{info, warn, error};
 
 fn main() -> Result<(), ::std::io::Error> {
 
     println!("Rustylight");
-    println!("");
+    println!();
     println!("Starting, hang on...");
 
     let config_result = configuration::load();
@@ -27,41 +40,97 @@ fn main() -> Result<(), ::std::io::Error> {
 
     let config = config_result.unwrap();
     let show = shows::load_show(&config);
-    let dmx_port = enttec_devices::open_dmxis_port(&config);
+    let dmx_outputs = dmx_outputs::open_dmx_outputs(&config);
     let midi_port = midi_ports::new(&config);
+    let midi_feedback = midi_feedback::new(&config);
     info!("");
 
-    if !show.is_some() || dmx_port.is_err() || !midi_port.is_some() {
+    if show.is_none() || dmx_outputs.is_err() || midi_port.is_none() || midi_feedback.is_err() {
         error!("Destroying the application. See logs for further details.");
         error!("Bye!");
         error!("");
         return Ok(());
     }
 
-    start_game_loop(&config, show.unwrap(), dmx_port.unwrap(), midi_port.unwrap());
+    let running = Arc::new(AtomicBool::new(true));
+    let running_handler = running.clone();
+    if let Err(err) = ctrlc::set_handler(move || running_handler.store(false, Ordering::SeqCst)) {
+        warn!("Couldn't register shutdown handler: {}", err);
+    }
+
+    start_game_loop(config, show.unwrap(), dmx_outputs.unwrap(), midi_port.unwrap(), midi_feedback.unwrap(), &running);
 
+    info!("");
+    info!("Bye!");
     return Ok(());
 }
 
-fn start_game_loop(config: &BaseConfig, mut show: Show, mut dmx_port: Dmxis, midi_port: MidiPort) {
-    let frame_duration = 1000/config.fps;
+fn start_game_loop(mut config: BaseConfig, mut show: Show, mut dmx_outputs: Vec<Box<dyn DmxOutput>>, mut midi_port: MidiPort, mut midi_feedback: Option<MidiFeedback>, running: &AtomicBool) {
+    let config_watcher = configuration::watch();
+    let mut learn_target = midi_learn::start(&config, &mut midi_port);
+    let mut frame_duration = 1000/config.fps;
     let mut sleep_duration;
     show.print_content();
     info!("");
     info!("Here we go!");
     info!("");
-    loop {
+    while running.load(Ordering::SeqCst) {
         let loop_start_time = Instant::now();
 
+        // Apply config changes made while running
+        if let Some(watcher) = &config_watcher {
+            if watcher.has_changed() {
+                if let Some(new_config) = configuration::reload() {
+                    config = new_config;
+                    frame_duration = 1000/config.fps;
+                    for dmx_output in dmx_outputs.iter_mut() {
+                        dmx_output.update_config(&config);
+                    }
+                    midi_port.update_config(&config);
+                    learn_target = midi_learn::start(&config, &mut midi_port);
+                    midi_feedback = midi_feedback::update_config(midi_feedback, &config);
+                    info!("Config reloaded");
+                }
+            }
+        }
+
         // Read all inputs
         let update = midi_port.read_all();
 
+        // Bind a message received in learn mode
+        if let Some(learned) = midi_port.take_learned() {
+            if let Some(target) = learn_target.take() {
+                midi_learn::apply(target, learned, &mut config, &mut show, &mut midi_port);
+            }
+        }
+
+        let capture = update.capture.is_some();
+
         // Update internal state
         show.update_state(update, &config);
 
+        // Report the state back to the controller
+        if let Some(feedback) = &mut midi_feedback {
+            feedback.update(&show);
+        }
+
         // Render internal state to DMX
-        let dmx_data = show.get_dmx_data();
-        dmx_port.write(&dmx_data);
+        let mut dmx_data = show.get_dmx_data();
+
+        // Merge received DMX into the rendered universes
+        for dmx_output in dmx_outputs.iter_mut() {
+            if let Some(input) = dmx_output.read_input() {
+                if config.dmx_input_merge {
+                    shows::merge_input(&mut dmx_data, config.dmx_input_universe, &input);
+                }
+                if capture {
+                    shows::capture_scene(&config.dmx_capture_path, config.dmx_input_universe, &input);
+                }
+            }
+        }
+        for dmx_output in dmx_outputs.iter_mut() {
+            dmx_output.write(&dmx_data);
+        }
 
         // Fill remaining frame with idle time
         let elapsed = loop_start_time.elapsed().as_millis() as u64;
@@ -73,4 +142,10 @@ fn start_game_loop(config: &BaseConfig, mut show: Show, mut dmx_port: Dmxis, mid
         }
         sleep(Duration::from_millis(sleep_duration));
     }
+    for dmx_output in dmx_outputs.iter_mut() {
+        dmx_output.close();
+    }
+    if let Some(feedback) = &mut midi_feedback {
+        feedback.close();
+    }
 }
@@ -0,0 +1,205 @@
+use midir::{MidiOutput, MidiOutputConnection};
+use crate::configuration::BaseConfig;
+use crate::midi_ports::{PROGRAMM_CHANGE, CONTROL_CHANGE, NOTE_ON, NOTE_OFF};
+use crate::shows::Show;
+use std::error::Error;
+use std::time::Instant;
+use log::{info, error};
+
+const LED_ON: u8 = 127;
+const LED_OFF: u8 = 0;
+
+// Sends the selected song and scene and a tempo blink to a controller, so its LEDs and display show the current state.
+// Only changes are sent to keep the traffic low.
+pub struct MidiFeedback {
+    connection: MidiOutputConnection,
+    port: String,
+    channel: u8,
+    song_message: String,
+    song_number: u8,
+    scene_message: String,
+    scene_number: u8,
+    tempo_message: String,
+    tempo_number: u8,
+    sent_song: Option<usize>,
+    sent_scene: Option<usize>,
+    sent_blink: Option<bool>,
+}
+
+impl MidiFeedback {
+    fn matches(&self, config: &BaseConfig) -> bool {
+        self.port == config.midi_feedback_port
+            && self.channel + 1 == config.midi_feedback_channel
+            && self.song_message == config.midi_feedback_song
+            && self.song_number == config.midi_feedback_song_number
+            && self.scene_message == config.midi_feedback_scene
+            && self.scene_number == config.midi_feedback_scene_number
+            && self.tempo_message == config.midi_feedback_tempo
+            && self.tempo_number == config.midi_feedback_tempo_number
+    }
+
+    pub fn update(&mut self, show: &Show) {
+        let song = show.get_selected_song();
+        if self.sent_song != Some(song) {
+            self.send_selection(&self.song_message.clone(), self.song_number, self.sent_song, song);
+            self.sent_song = Some(song);
+        }
+
+        if let Some(scene) = show.get_selected_scene() {
+            if self.sent_scene != Some(scene) {
+                self.send_selection(&self.scene_message.clone(), self.scene_number, self.sent_scene, scene);
+                self.sent_scene = Some(scene);
+            }
+        }
+
+        // The LED is lit for the first half of every beat
+        let blink = show.get_beat_position(Instant::now()).rem_euclid(1.0) < 0.5;
+        if self.sent_blink != Some(blink) {
+            let value = if blink { LED_ON } else { LED_OFF };
+            match self.tempo_message.as_str() {
+                "note" => self.send_note(self.tempo_number, value),
+                "cc" => self.send(&[CONTROL_CHANGE + self.channel, self.tempo_number, value]),
+                _ => (),
+            }
+            self.sent_blink = Some(blink);
+        }
+    }
+
+    // Selections are sent as program change or control change with the index as value,
+    // or as note LEDs starting at the configured note where only the selected one is lit
+    fn send_selection(&mut self, message: &str, number: u8, previous: Option<usize>, selected: usize) {
+        let value = selected.min(127) as u8;
+        match message {
+            "pc" => self.send(&[PROGRAMM_CHANGE + self.channel, value]),
+            "cc" => self.send(&[CONTROL_CHANGE + self.channel, number, value]),
+            "notes" => {
+                if let Some(previous) = previous {
+                    self.send_note(number.saturating_add(previous.min(127) as u8).min(127), LED_OFF);
+                }
+                self.send_note(number.saturating_add(value).min(127), LED_ON);
+            },
+            _ => (),
+        }
+    }
+
+    fn send_note(&mut self, note: u8, velocity: u8) {
+        if velocity > 0 {
+            self.send(&[NOTE_ON + self.channel, note, velocity]);
+        } else {
+            self.send(&[NOTE_OFF + self.channel, note, 0]);
+        }
+    }
+
+    fn send(&mut self, message: &[u8]) {
+        if let Err(err) = self.connection.send(message) {
+            error!("MIDI feedback was not successfully sent: {}", err);
+        }
+    }
+
+    // Switches off all LEDs that were lit, so the controller doesn't show a stale state
+    pub fn close(&mut self) {
+        if self.scene_message == "notes" {
+            if let Some(scene) = self.sent_scene {
+                self.send_note(self.scene_number.saturating_add(scene.min(127) as u8).min(127), LED_OFF);
+            }
+        }
+        if self.song_message == "notes" {
+            if let Some(song) = self.sent_song {
+                self.send_note(self.song_number.saturating_add(song.min(127) as u8).min(127), LED_OFF);
+            }
+        }
+        match self.tempo_message.as_str() {
+            "note" => self.send_note(self.tempo_number, LED_OFF),
+            "cc" => self.send(&[CONTROL_CHANGE + self.channel, self.tempo_number, LED_OFF]),
+            _ => (),
+        }
+    }
+}
+
+pub fn new(config: &BaseConfig) -> Result<Option<MidiFeedback>, Box<dyn Error>> {
+    if config.midi_feedback_port.is_empty() {
+        return Ok(None);
+    }
+    for (setting, value, options) in [
+        ("midi_feedback_song", &config.midi_feedback_song, ["none", "pc", "cc", "notes"].as_slice()),
+        ("midi_feedback_scene", &config.midi_feedback_scene, ["none", "pc", "cc", "notes"].as_slice()),
+        ("midi_feedback_tempo", &config.midi_feedback_tempo, ["none", "note", "cc"].as_slice()),
+    ] {
+        if !options.contains(&value.as_str()) {
+            error!("");
+            error!("!!  Unknown {} '{}', use '{}'.  !!", setting, value, options.join("', '"));
+            error!("");
+            return Err("".into());
+        }
+    }
+    if !(1..=16).contains(&config.midi_feedback_channel) {
+        error!("");
+        error!("!!  Invalid midi_feedback_channel {}, use 1-16.  !!", config.midi_feedback_channel);
+        error!("");
+        return Err("".into());
+    }
+
+    let midi_out = MidiOutput::new("midir feedback output")?;
+    let ports = midi_out.ports();
+    let mut port = None;
+    for p in ports.iter() {
+        if midi_out.port_name(p)?.contains(&config.midi_feedback_port) {
+            port = Some(p);
+            break;
+        }
+    }
+    if port.is_none() {
+        error!("");
+        error!("!!  Couldn't find {} in available midi output ports.  !!", config.midi_feedback_port);
+        error!("    Available midi output ports are:");
+        for p in ports.iter() {
+            error!("    - {}", midi_out.port_name(p)?);
+        }
+        error!("");
+        return Err("".into());
+    }
+    let port = port.unwrap();
+    let port_name = midi_out.port_name(port)?;
+    let connection = midi_out.connect(port, "midir-feedback-output");
+    if let Err(err) = connection {
+        error!("");
+        error!("!!  Couldn't connect to midi output port {}: {}  !!", port_name, err);
+        error!("");
+        return Err("".into());
+    }
+    info!("Connected midi output:   {}", port_name);
+    Ok(Some(MidiFeedback {
+        connection: connection.unwrap(),
+        port: config.midi_feedback_port.clone(),
+        channel: config.midi_feedback_channel - 1,
+        song_message: config.midi_feedback_song.clone(),
+        song_number: config.midi_feedback_song_number,
+        scene_message: config.midi_feedback_scene.clone(),
+        scene_number: config.midi_feedback_scene_number,
+        tempo_message: config.midi_feedback_tempo.clone(),
+        tempo_number: config.midi_feedback_tempo_number,
+        sent_song: None,
+        sent_scene: None,
+        sent_blink: None,
+    }))
+}
+
+// Reconnects with the new settings when the feedback part of the config changed while running.
+// The LEDs of the former settings are switched off first, an invalid config disables the feedback.
+pub fn update_config(midi_feedback: Option<MidiFeedback>, config: &BaseConfig) -> Option<MidiFeedback> {
+    if let Some(mut feedback) = midi_feedback {
+        if feedback.matches(config) {
+            return Some(feedback);
+        }
+        feedback.close();
+    } else if config.midi_feedback_port.is_empty() {
+        return None;
+    }
+    match new(config) {
+        Ok(feedback) => feedback,
+        Err(_) => {
+            error!("MIDI feedback is disabled until the config is fixed");
+            None
+        }
+    }
+}
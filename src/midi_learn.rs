@@ -0,0 +1,84 @@
+use serde_yaml::Value;
+use log::{info, error};
+use crate::configuration::{self, BaseConfig, MidiMapping};
+use crate::midi_mappings::{default_mappings, is_action};
+use crate::midi_ports::{LearnedMessage, MidiPort};
+use crate::shows::Show;
+
+const FADER_PREFIX: &str = "fader:";
+
+// Learn binds the next received message either to an action in midi_mappings or to a fader of the current scene
+pub enum LearnTarget {
+    Action(String),
+    Fader(Value),
+}
+
+// Arms or disarms learn mode according to the midi_learn config entry
+pub fn start(config: &BaseConfig, midi_port: &mut MidiPort) -> Option<LearnTarget> {
+    let target = parse_target(&config.midi_learn);
+    match &target {
+        Some(LearnTarget::Action(action)) => {
+            midi_port.start_learn(true);
+            info!("MIDI learn:              {}, send a note, control change or program change", action);
+        },
+        Some(LearnTarget::Fader(_)) => {
+            midi_port.start_learn(false);
+            info!("MIDI learn:              {}, send a note or control change", config.midi_learn);
+        },
+        None => midi_port.stop_learn(),
+    }
+    target
+}
+
+pub fn apply(target: LearnTarget, learned: LearnedMessage, config: &mut BaseConfig, show: &mut Show, midi_port: &mut MidiPort) {
+    match target {
+        LearnTarget::Action(action) => {
+            // The defaults are kept when learning the first mapping, a learned action replaces its former mappings
+            if config.midi_mappings.is_empty() {
+                config.midi_mappings = default_mappings(config);
+            }
+            config.midi_mappings.retain(|mapping| mapping.action != action);
+            config.midi_mappings.insert(0, MidiMapping {
+                action: action.clone(),
+                message: String::from(learned.message),
+                channel: learned.channel,
+                // Program changes select the song or scene with their number, other actions react to the learned program only
+                number: if learned.message == "pc" && (action == "select_song" || action == "select_scene") { None } else { Some(learned.number) },
+                value: None,
+            });
+            midi_port.update_config(config);
+            info!("Learned:                 {} {} on channel {} for {}", learned.message, learned.number, learned.channel, action);
+        },
+        LearnTarget::Fader(address) => {
+            let (fader_type, param) = if learned.message == "note" { ("midi", "note") } else { ("cc", "cc") };
+            match show.learn_fader(&address, fader_type, param, learned.number) {
+                Ok(scene_path) => info!("Learned:                 {} {} for fader {:?} in {}", learned.message, learned.number, address, scene_path),
+                Err(err) => error!("Couldn't learn fader {:?}: {}", address, err),
+            }
+        },
+    }
+    // Learning is done once, the config is stored without midi_learn so it isn't armed again
+    config.midi_learn = String::new();
+    configuration::store(config);
+}
+
+fn parse_target(setting: &str) -> Option<LearnTarget> {
+    if setting.is_empty() {
+        return None;
+    }
+    if let Some(address) = setting.strip_prefix(FADER_PREFIX) {
+        // Addresses are written like the fader keys in the scene yaml, a number or 'universe:channel'
+        let address = match address.trim().parse::<u64>() {
+            Ok(channel) => Value::from(channel),
+            Err(_) => Value::from(address.trim()),
+        };
+        return Some(LearnTarget::Fader(address));
+    }
+    if is_action(setting) {
+        return Some(LearnTarget::Action(String::from(setting)));
+    }
+    error!("");
+    error!("!!  Unknown midi_learn '{}', use a midi mapping action like 'next_scene' or a fader like 'fader:2:17'.  !!", setting);
+    error!("");
+    None
+}
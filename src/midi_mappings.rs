@@ -0,0 +1,262 @@
+use log::{error};
+use crate::configuration::{BaseConfig, MidiMapping};
+use crate::midi_ports::{PROGRAMM_CHANGE, CONTROL_CHANGE, NOTE_ON};
+
+// Controls used when no midi_mappings are configured
+const SONG_SELECT: u8 = 0;
+const ALL_NOTES_OFF: u8 = 123;
+const CAPTURE_INPUT: u8 = 119;
+const TEMPO_CONTROL_1: u8 = 12;
+const TEMPO_CONTROL_2: u8 = 13;
+const TEMPO_CONTROL_LSB: u8 = 44; // LSB of Effect Control 1 for 14 bit tempo
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MidiAction {
+    SelectSong,
+    SelectScene,
+    NextScene,
+    PreviousScene,
+    Blackout,
+    TapTempo,
+    SetTempo,
+    SetTempoMsb,
+    SetTempoLsb,
+    CaptureInput,
+}
+
+impl MidiAction {
+    // Triggers only react to a message, they don't use its value
+    fn is_trigger(&self) -> bool {
+        matches!(self, MidiAction::NextScene | MidiAction::PreviousScene | MidiAction::Blackout | MidiAction::TapTempo | MidiAction::CaptureInput)
+    }
+}
+
+pub struct MidiBinding {
+    pub action: MidiAction,
+    message_type: u8,
+    channel: Option<u8>,
+    number: Option<u8>,
+    value: Option<usize>,
+}
+
+impl MidiBinding {
+    // Returns the value for the action if the message matches this binding.
+    // Bindings without channel react to all channels the receiving input listens on.
+    pub fn value_of(&self, status: u8, data1: u8, data2: Option<u8>, on_input_channel: bool) -> Option<usize> {
+        let channel_matches = match self.channel {
+            Some(channel) => status & 0x0F == channel,
+            None => on_input_channel,
+        };
+        if status & 0xF0 != self.message_type || !channel_matches {
+            return None;
+        }
+        let data = if self.message_type == PROGRAMM_CHANGE {
+            if self.number.is_some_and(|number| number != data1) {
+                return None;
+            }
+            data1
+        } else {
+            // Note ons with a velocity of 0 are releases
+            if self.message_type == NOTE_ON && data2.unwrap_or(0) == 0 {
+                return None;
+            }
+            match self.number {
+                Some(number) if number != data1 => return None,
+                Some(_) => data2?,
+                None => data1,
+            }
+        } as usize;
+        if self.action.is_trigger() {
+            match self.value {
+                Some(value) if value != data => return None,
+                None if self.message_type == CONTROL_CHANGE && self.number.is_some() && data == 0 => return None,
+                _ => (),
+            }
+            return Some(data);
+        }
+        Some(self.value.unwrap_or(data))
+    }
+}
+
+fn parse_action(action: &str) -> Option<MidiAction> {
+    match action {
+        "select_song" => Some(MidiAction::SelectSong),
+        "select_scene" => Some(MidiAction::SelectScene),
+        "next_scene" => Some(MidiAction::NextScene),
+        "previous_scene" => Some(MidiAction::PreviousScene),
+        "blackout" => Some(MidiAction::Blackout),
+        "tap_tempo" => Some(MidiAction::TapTempo),
+        "set_tempo" => Some(MidiAction::SetTempo),
+        "set_tempo_msb" => Some(MidiAction::SetTempoMsb),
+        "set_tempo_lsb" => Some(MidiAction::SetTempoLsb),
+        "capture_input" => Some(MidiAction::CaptureInput),
+        _ => None,
+    }
+}
+
+pub fn is_action(action: &str) -> bool {
+    parse_action(action).is_some()
+}
+
+fn binding_from_mapping(mapping: &MidiMapping) -> Option<MidiBinding> {
+    let action = parse_action(&mapping.action);
+    if action.is_none() {
+        error!("");
+        error!("!!  Unknown midi mapping action '{}'.  !!", mapping.action);
+        error!("    Use 'select_song', 'select_scene', 'next_scene', 'previous_scene', 'blackout', 'tap_tempo', 'set_tempo', 'set_tempo_msb', 'set_tempo_lsb' or 'capture_input'.");
+        error!("");
+        return None;
+    }
+    let message_type = match mapping.message.as_str() {
+        "note" => NOTE_ON,
+        "cc" => CONTROL_CHANGE,
+        "pc" => PROGRAMM_CHANGE,
+        _ => {
+            error!("");
+            error!("!!  Unknown midi mapping message '{}' for '{}', use 'note', 'cc' or 'pc'.  !!", mapping.message, mapping.action);
+            error!("");
+            return None;
+        }
+    };
+    // Channel 0 uses the channels of the midi inputs
+    if mapping.channel > 16 {
+        error!("");
+        error!("!!  Invalid channel {} in midi mapping for '{}', use 1-16.  !!", mapping.channel, mapping.action);
+        error!("");
+        return None;
+    }
+    Some(MidiBinding {
+        action: action?,
+        message_type,
+        channel: mapping.channel.checked_sub(1),
+        number: mapping.number,
+        value: mapping.value,
+    })
+}
+
+// Rebuilds the former fixed assignments so configs without midi_mappings keep working
+pub fn default_mappings(config: &BaseConfig) -> Vec<MidiMapping> {
+    let mapping = |action: &str, message: &str, number: Option<u8>, value: Option<usize>| MidiMapping {
+        action: String::from(action),
+        message: String::from(message),
+        channel: 0,
+        number,
+        value,
+    };
+    let mut mappings = Vec::new();
+    if config.tap_tempo_message == "note" || config.tap_tempo_message == "cc" {
+        mappings.push(mapping("tap_tempo", &config.tap_tempo_message, Some(config.tap_tempo_number), None));
+    }
+    mappings.push(mapping("select_scene", "pc", None, None));
+    mappings.push(mapping("select_song", "cc", Some(SONG_SELECT), None));
+    if config.midi_tempo_mode == "14bit" {
+        mappings.push(mapping("set_tempo_msb", "cc", Some(TEMPO_CONTROL_1), None));
+        mappings.push(mapping("set_tempo_lsb", "cc", Some(TEMPO_CONTROL_LSB), None));
+    } else {
+        mappings.push(mapping("set_tempo", "cc", Some(TEMPO_CONTROL_1), None));
+        mappings.push(mapping("set_tempo", "cc", Some(TEMPO_CONTROL_2), None));
+    }
+    mappings.push(mapping("blackout", "cc", Some(ALL_NOTES_OFF), Some(0)));
+    mappings.push(mapping("capture_input", "cc", Some(CAPTURE_INPUT), None));
+    mappings
+}
+
+pub fn bindings_from_config(config: &BaseConfig) -> Option<Vec<MidiBinding>> {
+    if config.midi_mappings.is_empty() {
+        if config.midi_tempo_mode != "sum" && config.midi_tempo_mode != "14bit" {
+            error!("");
+            error!("!!  Unknown midi_tempo_mode '{}', use 'sum' or '14bit'.  !!", config.midi_tempo_mode);
+            error!("");
+            return None;
+        }
+        if !["none", "note", "cc"].contains(&config.tap_tempo_message.as_str()) {
+            error!("");
+            error!("!!  Unknown tap_tempo_message '{}', use 'none', 'note' or 'cc'.  !!", config.tap_tempo_message);
+            error!("");
+            return None;
+        }
+    }
+    let mappings = if config.midi_mappings.is_empty() { default_mappings(config) } else { config.midi_mappings.clone() };
+    let mut bindings = Vec::new();
+    for mapping in mappings.iter() {
+        bindings.push(binding_from_mapping(mapping)?);
+    }
+    Some(bindings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(action: &str, message: &str, channel: u8, number: Option<u8>, value: Option<usize>) -> MidiMapping {
+        MidiMapping { action: String::from(action), message: String::from(message), channel, number, value }
+    }
+
+    fn binding(action: &str, message: &str, channel: u8, number: Option<u8>, value: Option<usize>) -> MidiBinding {
+        binding_from_mapping(&mapping(action, message, channel, number, value)).unwrap()
+    }
+
+    #[test]
+    fn program_changes_select_by_number() {
+        let select_scene = binding("select_scene", "pc", 0, None, None);
+        assert_eq!(select_scene.value_of(PROGRAMM_CHANGE, 5, None, true), Some(5));
+        assert_eq!(select_scene.value_of(PROGRAMM_CHANGE, 5, None, false), None);
+        assert_eq!(select_scene.value_of(CONTROL_CHANGE, 5, Some(1), true), None);
+
+        let blackout = binding("blackout", "pc", 0, Some(9), None);
+        assert_eq!(blackout.value_of(PROGRAMM_CHANGE, 9, None, true), Some(9));
+        assert_eq!(blackout.value_of(PROGRAMM_CHANGE, 8, None, true), None);
+    }
+
+    #[test]
+    fn channels_of_mappings_override_the_input_channels() {
+        let select_song = binding("select_song", "cc", 3, Some(0), None);
+        assert_eq!(select_song.value_of(CONTROL_CHANGE + 2, 0, Some(4), false), Some(4));
+        assert_eq!(select_song.value_of(CONTROL_CHANGE, 0, Some(4), true), None);
+    }
+
+    #[test]
+    fn triggers_react_to_values_above_0_or_the_given_value() {
+        let next_scene = binding("next_scene", "cc", 0, Some(20), None);
+        assert_eq!(next_scene.value_of(CONTROL_CHANGE, 20, Some(127), true), Some(127));
+        assert_eq!(next_scene.value_of(CONTROL_CHANGE, 20, Some(0), true), None);
+        assert_eq!(next_scene.value_of(CONTROL_CHANGE, 21, Some(127), true), None);
+
+        let blackout = binding("blackout", "cc", 0, Some(123), Some(0));
+        assert_eq!(blackout.value_of(CONTROL_CHANGE, 123, Some(0), true), Some(0));
+        assert_eq!(blackout.value_of(CONTROL_CHANGE, 123, Some(1), true), None);
+
+        let tap_tempo = binding("tap_tempo", "note", 0, Some(36), None);
+        assert_eq!(tap_tempo.value_of(NOTE_ON, 36, Some(100), true), Some(100));
+        assert_eq!(tap_tempo.value_of(NOTE_ON, 36, Some(0), true), None);
+    }
+
+    #[test]
+    fn fixed_values_replace_the_received_value() {
+        let set_tempo = binding("set_tempo", "note", 0, Some(60), Some(128));
+        assert_eq!(set_tempo.value_of(NOTE_ON, 60, Some(1), true), Some(128));
+        let select_scene = binding("select_scene", "note", 0, None, None);
+        assert_eq!(select_scene.value_of(NOTE_ON, 7, Some(1), true), Some(7));
+    }
+
+    #[test]
+    fn invalid_mappings_are_rejected() {
+        assert!(binding_from_mapping(&mapping("jump", "cc", 0, None, None)).is_none());
+        assert!(binding_from_mapping(&mapping("blackout", "sysex", 0, None, None)).is_none());
+        assert!(binding_from_mapping(&mapping("blackout", "cc", 17, None, None)).is_none());
+    }
+
+    #[test]
+    fn defaults_follow_the_tempo_mode() {
+        let mut config = BaseConfig::default();
+        config.midi_tempo_mode = String::from("14bit");
+        let bindings = bindings_from_config(&config).unwrap();
+        assert!(bindings.iter().any(|binding| binding.action == MidiAction::SetTempoMsb));
+        assert!(!bindings.iter().any(|binding| binding.action == MidiAction::SetTempo));
+
+        config.midi_tempo_mode = String::from("fast");
+        assert!(bindings_from_config(&config).is_none());
+        config.midi_mappings = vec![mapping("blackout", "cc", 0, Some(123), None)];
+        assert_eq!(bindings_from_config(&config).unwrap().len(), 1);
+    }
+}
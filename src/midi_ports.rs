@@ -1,42 +1,74 @@
-use midir::{MidiInput, MidiInputConnection};
-use crate::configuration::BaseConfig;
-use crate::shows::ShowUpdate;
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use crate::configuration::{BaseConfig, MidiInputPort};
+use crate::shows::{ShowUpdate, Transport};
+use crate::midi_mappings::{MidiAction, MidiBinding, bindings_from_config};
+use crate::mtc::MtcReader;
+use crate::tempo::{MidiClock, TapTempo};
 use std::error::Error;
-use crossbeam_channel::{unbounded, Receiver};
+use std::time::Instant;
+use crossbeam_channel::{unbounded, Receiver, Sender};
 use log::{info, trace, error};
 
 pub struct MidiMessage {
     status: u8,
     data1: u8,
     data2: Option<u8>,
+    time: Instant,
+    on_input_channel: bool,
+    sysex: Vec<u8>,
+}
+
+// A message received in learn mode, with the channel numbered from 1
+pub struct LearnedMessage {
+    pub message: &'static str,
+    pub channel: u8,
+    pub number: u8,
 }
 
 pub struct MidiPort {
-    midi_channel: u8,
-    midi_port: String,
-    connection: Option<MidiInputConnection<()>>,
-    receiver: Option<Receiver<MidiMessage>>
+    midi_mode: String,
+    inputs: Vec<MidiInputPort>,
+    connections: Vec<MidiInputConnection<()>>,
+    receiver: Option<Receiver<MidiMessage>>,
+    clock: Option<MidiClock>,
+    timecode: Option<MtcReader>,
+    bindings: Vec<MidiBinding>,
+    tempo_msb: Option<usize>,
+    tap_tempo: TapTempo,
+    learning: Option<bool>, // whether program changes can be learned
+    learned: Option<LearnedMessage>,
 }
 
-const PROGRAMM_CHANGE: u8 = 192; // programm changes have a status range from 192-207
-const CONTROL_CHANGE: u8 = 176; // control changes have a status range from 176-191
-const NOTE_ON: u8 = 144; // note on events have a status range from 144-159
-const NOTE_OFF: u8 = 128; // note off events have a status range from 128-143
-const SONG_SELECT: u8 = 0;
-const ALL_NOTES_OFF: u8 = 123;
-const TEMPO_CONTROL_1: u8 = 12;
-const TEMPO_CONTROL_2: u8 = 13;
+pub const PROGRAMM_CHANGE: u8 = 192; // programm changes have a status range from 192-207
+pub const CONTROL_CHANGE: u8 = 176; // control changes have a status range from 176-191
+pub const NOTE_ON: u8 = 144; // note on events have a status range from 144-159
+pub const NOTE_OFF: u8 = 128; // note off events have a status range from 128-143
+const TEMPO_STEPS_PER_BPM: f64 = 10.0; // 14 bit tempo is sent in tenths of a BPM
+const TIMING_CLOCK: u8 = 248; // system real time messages have no channel
+const START: u8 = 250;
+const CONTINUE: u8 = 251;
+const STOP: u8 = 252;
+const SONG_POSITION_POINTER: u8 = 242;
+const SYSTEM_EXCLUSIVE: u8 = 240;
+const TIMECODE_QUARTER_FRAME: u8 = 241;
 
 impl MidiPort {
-    pub fn connect (&mut self) -> Result<(), Box<dyn Error>> {
-        let midi_in = MidiInput::new("midir reading input")?;
+    pub fn connect (&mut self, input: &MidiInputPort, sender: Sender<MidiMessage>) -> Result<(), Box<dyn Error>> {
+        let mut midi_in = MidiInput::new("midir reading input")?;
+        midi_in.ignore(Ignore::None);
         let ports = midi_in.ports();
-        let port_result = ports.iter().find(|p| midi_in.port_name(p).unwrap().contains(&self.midi_port));
+        let mut port_result = None;
+        for p in ports.iter() {
+            if midi_in.port_name(p)?.contains(&input.port) {
+                port_result = Some(p);
+                break;
+            }
+        }
         let port;
 
         if port_result.is_none() {
             error!("");
-            error!("!!  Couldn't find {} in available midi ports.  !!", self.midi_port);
+            error!("!!  Couldn't find {} in available midi ports.  !!", input.port);
             error!("    Available midi input ports are:");
             for p in ports.iter() {
                 error!("    - {}", midi_in.port_name(&p)?);
@@ -46,60 +78,229 @@ impl MidiPort {
         }
 
         port = port_result.unwrap();
-        info!("Connected midi port:     {}", midi_in.port_name(port)?);
-        let (sender, receiver) = unbounded();
-        self.receiver = Some(receiver);
+        info!("Connected midi port:     {} ({})", midi_in.port_name(port)?, describe_channels(&input.channels));
+        let channels = input.channels.clone();
 
         let connection = midi_in.connect(&port, "midir-read-input", move |_stamp, message, _| {
-            let parsed_message = parse_midi_message(message);
-            if let Some(payload) = parsed_message {
-                trace!("MIDI Message: s {} - d1 {} - d2 {:?}", payload.status, payload.data1, payload.data2);
-                match sender.try_send(payload) {
-                    Ok(()) => (),
-                    Err(err) => error!("{}", err),
-                };
-            }
+            forward_midi_message(&sender, message, &channels);
         }, ());
-        self.connection = connection.ok();
+        if let Ok(connection) = connection {
+            self.connections.push(connection);
+        }
 
         return Ok(());
     }
 
-    pub fn read_all(&self) -> ShowUpdate {
+    // Creates a port other applications like DAWs or aconnect can send to
+    #[cfg(unix)]
+    pub fn create_virtual(&mut self, input: &MidiInputPort, sender: Sender<MidiMessage>) -> Result<(), Box<dyn Error>> {
+        use midir::os::unix::VirtualInput;
+        let mut midi_in = MidiInput::new("midir reading input")?;
+        midi_in.ignore(Ignore::None);
+        let channels = input.channels.clone();
+        let connection = midi_in.create_virtual(&input.virtual_port, move |_stamp, message, _| {
+            forward_midi_message(&sender, message, &channels);
+        }, ());
+        match connection {
+            Ok(connection) => {
+                info!("Created midi port:       {} ({})", input.virtual_port, describe_channels(&input.channels));
+                self.connections.push(connection);
+                Ok(())
+            },
+            Err(err) => {
+                error!("");
+                error!("!!  Couldn't create virtual midi port {}: {}  !!", input.virtual_port, err);
+                error!("");
+                Err("".into())
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn create_virtual(&mut self, _input: &MidiInputPort, _sender: Sender<MidiMessage>) -> Result<(), Box<dyn Error>> {
+        error!("");
+        error!("!!  Virtual midi ports are not supported on this system, use midi_mode 'port' or 'none'.  !!");
+        error!("");
+        Err("".into())
+    }
+
+    // All inputs send to the same channel, so their messages are merged into one update per frame
+    fn connect_inputs(&mut self) -> Result<(), Box<dyn Error>> {
+        let (sender, receiver) = unbounded();
+        for input in self.inputs.clone().iter() {
+            if let Some(channel) = input.channels.iter().find(|channel| !(1..=16).contains(*channel)) {
+                error!("");
+                error!("!!  Invalid channel {} for midi input '{}{}', use 1-16.  !!", channel, input.port, input.virtual_port);
+                error!("");
+                return Err("".into());
+            }
+            // An empty name would be contained in every port name and connect to whatever port comes first
+            if input.port.is_empty() && input.virtual_port.is_empty() {
+                error!("");
+                error!("!!  Midi input without port name, set 'port' or 'virtual_port' of the midi input or midi_port.  !!");
+                error!("");
+                return Err("".into());
+            }
+            // The same port twice would deliver every message twice
+            let duplicates = self.inputs.iter().filter(|other| other.port == input.port && other.virtual_port == input.virtual_port).count();
+            if duplicates > 1 {
+                error!("");
+                error!("!!  Midi input '{}{}' is configured more than once.  !!", input.port, input.virtual_port);
+                error!("");
+                return Err("".into());
+            }
+            if input.virtual_port.is_empty() {
+                self.connect(input, sender.clone())?;
+            } else {
+                self.create_virtual(input, sender.clone())?;
+            }
+        }
+        self.receiver = Some(receiver);
+        Ok(())
+    }
+
+    // Mappings can change while running, invalid ones keep the current bindings
+    pub fn update_config(&mut self, config: &BaseConfig) {
+        if let Some(bindings) = bindings_from_config(config) {
+            self.bindings = bindings;
+        }
+    }
+
+    // The next note on, control change or program change is learned instead of being handled
+    pub fn start_learn(&mut self, program_changes: bool) {
+        self.learning = Some(program_changes);
+        self.learned = None;
+    }
+
+    pub fn stop_learn(&mut self) {
+        self.learning = None;
+    }
+
+    pub fn take_learned(&mut self) -> Option<LearnedMessage> {
+        self.learned.take()
+    }
+
+    pub fn read_all(&mut self) -> ShowUpdate {
         let mut update = ShowUpdate {
             song: None,
             scene: None,
+            scene_step: None,
             tempo: None,
             off: None,
+            capture: None,
+            transport: None,
+            beat: None,
+            timecode: None,
             notes: [None; 128],
+            controls: [None; 128],
+            note_hits: [None; 128],
         };
         if let Some(receiver) = &self.receiver {
-            let mut tempo1 = None;
-            let mut tempo2 = None;
+            let mut tempo_values: Vec<Option<usize>> = vec![None; self.bindings.len()];
+            let mut fine_tempo = None;
+            let mut tapped_tempo = None;
             loop {
                 match receiver.try_recv() {
                     Ok(message) => {
-                        if message.status == PROGRAMM_CHANGE + &self.midi_channel {
-                            update.scene = Some(message.data1 as usize);
-                        } else if message.status == CONTROL_CHANGE + &self.midi_channel && message.data1 == SONG_SELECT && message.data2.is_some() {
-                            update.song = Some(message.data2.unwrap() as usize);
-                        } else if message.status == CONTROL_CHANGE + &self.midi_channel && message.data1 == TEMPO_CONTROL_1 && message.data2.is_some() {
-                            tempo1 = message.data2;
-                        } else if message.status == CONTROL_CHANGE + &self.midi_channel && message.data1 == TEMPO_CONTROL_2 && message.data2.is_some() {
-                            tempo2 = message.data2;
-                        } else if message.status == CONTROL_CHANGE + &self.midi_channel && message.data1 == ALL_NOTES_OFF {
-                            update.off = Some(true);
-                        } else if message.status == NOTE_ON + &self.midi_channel && message.data2.is_some() {
+                        if let Some(clock) = &mut self.clock {
+                            match message.status {
+                                TIMING_CLOCK => clock.tick(message.time),
+                                START => {
+                                    clock.start();
+                                    update.transport = Some(Transport::Start);
+                                },
+                                CONTINUE => {
+                                    clock.resume();
+                                    update.transport = Some(Transport::Continue);
+                                },
+                                STOP => {
+                                    clock.stop();
+                                    update.transport = Some(Transport::Stop);
+                                },
+                                SONG_POSITION_POINTER => {
+                                    let position = message.data1 as u16 | (message.data2.unwrap_or(0) as u16) << 7;
+                                    clock.set_song_position(position);
+                                },
+                                _ => (),
+                            }
+                        }
+                        if let Some(timecode) = &mut self.timecode {
+                            match message.status {
+                                TIMECODE_QUARTER_FRAME => timecode.quarter_frame(message.data1, message.time),
+                                SYSTEM_EXCLUSIVE => timecode.full_frame(&message.sysex, message.time),
+                                _ => (),
+                            }
+                        }
+                        if let Some(learned) = self.learning.and_then(|program_changes| learn_message(&message, program_changes)) {
+                            self.learned = Some(learned);
+                            self.learning = None;
+                            continue;
+                        }
+                        // The first matching mapping wins, mapped notes don't trigger midi faders
+                        let mapped = self.bindings.iter().enumerate().find_map(|(index, binding)| {
+                            binding.value_of(message.status, message.data1, message.data2, message.on_input_channel).map(|value| (index, binding.action, value))
+                        });
+                        if let Some((index, action, value)) = mapped {
+                            match action {
+                                MidiAction::SelectSong => update.song = Some(value),
+                                MidiAction::SelectScene => update.scene = Some(value),
+                                MidiAction::NextScene => update.scene_step = Some(update.scene_step.unwrap_or(0) + 1),
+                                MidiAction::PreviousScene => update.scene_step = Some(update.scene_step.unwrap_or(0) - 1),
+                                MidiAction::Blackout => update.off = Some(true),
+                                MidiAction::CaptureInput => update.capture = Some(true),
+                                MidiAction::TapTempo => {
+                                    if let Some(tempo) = self.tap_tempo.tap(message.time) {
+                                        tapped_tempo = Some(tempo);
+                                    }
+                                },
+                                MidiAction::SetTempo => tempo_values[index] = Some(value),
+                                // The MSB is held, even across frames, until its LSB completes the tempo
+                                MidiAction::SetTempoMsb => self.tempo_msb = Some(value),
+                                MidiAction::SetTempoLsb => {
+                                    if let Some(tempo_msb) = self.tempo_msb {
+                                        fine_tempo = Some(((tempo_msb & 0x7F) << 7 | (value & 0x7F)) as f64 / TEMPO_STEPS_PER_BPM);
+                                    }
+                                },
+                            }
+                        } else if message.on_input_channel && message.status & 0xF0 == NOTE_ON && message.data2.is_some() {
                             update.notes[message.data1 as usize] = message.data2;
-                        } else if message.status == NOTE_OFF + &self.midi_channel {
+                            // Hits are kept per frame, the loudest one of a note counts
+                            if message.data2.unwrap_or(0) > 0 {
+                                update.note_hits[message.data1 as usize] = update.note_hits[message.data1 as usize].max(message.data2);
+                            }
+                        } else if message.on_input_channel && message.status & 0xF0 == NOTE_OFF {
                             update.notes[message.data1 as usize] = Some(0);
+                        } else if message.on_input_channel && message.status & 0xF0 == CONTROL_CHANGE && message.data2.is_some() {
+                            update.controls[message.data1 as usize] = message.data2;
                         }
                     },
                     Err(_) => break,
                 }
             }
-            if tempo1.is_some() && tempo2.is_some() {
-                update.tempo = Some(tempo1.unwrap() + tempo2.unwrap());
+            // Values of several set_tempo mappings are added up once all of them were received
+            let tempo_parts: Vec<Option<usize>> = self.bindings.iter().zip(tempo_values)
+                .filter(|(binding, _)| binding.action == MidiAction::SetTempo)
+                .map(|(_, value)| value)
+                .collect();
+            if !tempo_parts.is_empty() && tempo_parts.iter().all(|value| value.is_some()) {
+                update.tempo = Some(tempo_parts.iter().flatten().sum::<usize>() as f64);
+            }
+            if fine_tempo.is_some() {
+                update.tempo = fine_tempo;
+            }
+            if let Some(tempo) = tapped_tempo {
+                update.tempo = Some((tempo * 10.0).round() / 10.0);
+            }
+            // A running clock takes precedence over tempo control changes and taps
+            if let Some(clock) = &mut self.clock {
+                if let Some(clock_tempo) = clock.get_tempo(Instant::now()) {
+                    // Rounded to a tenth of a BPM so clock jitter doesn't change the tempo every frame
+                    update.tempo = Some((clock_tempo * 10.0).round() / 10.0);
+                }
+                update.beat = clock.take_beat();
+            }
+            if let Some(timecode) = &mut self.timecode {
+                update.timecode = timecode.take_position(Instant::now());
             }
         }
         update
@@ -108,29 +309,188 @@ impl MidiPort {
 
 pub fn new (config: &BaseConfig) -> Option<MidiPort> {
     let mut port = MidiPort {
-        midi_channel: config.midi_channel - 1, // to ease the calculation of midi messages later on
-        midi_port: config.midi_port.clone(),
-        connection: None,
+        midi_mode: config.midi_mode.clone(),
+        inputs: inputs_from_config(config),
+        connections: Vec::new(),
         receiver: None,
+        clock: if config.midi_clock { Some(MidiClock::new()) } else { None },
+        timecode: if config.midi_timecode { Some(MtcReader::new()) } else { None },
+        bindings: bindings_from_config(config)?,
+        tempo_msb: None,
+        tap_tempo: TapTempo::new(config.tap_tempo_taps, config.tap_tempo_timeout_ms),
+        learning: None,
+        learned: None,
+    };
+    let connected = match port.midi_mode.as_str() {
+        "port" | "virtual" => port.connect_inputs(),
+        "none" => {
+            info!("Midi input:              none");
+            Ok(())
+        },
+        _ => {
+            error!("");
+            error!("!!  Unknown midi_mode '{}', use 'port', 'virtual' or 'none'.  !!", port.midi_mode);
+            error!("");
+            Err("".into())
+        }
     };
-    if port.connect().is_ok() {
+    if connected.is_ok() {
         return Some(port);
     };
     None
 }
 
+// Without midi_inputs the single port of midi_mode listens on midi_channel
+fn inputs_from_config(config: &BaseConfig) -> Vec<MidiInputPort> {
+    if !config.midi_inputs.is_empty() {
+        return config.midi_inputs.clone();
+    }
+    let legacy_input = MidiInputPort {
+        port: config.midi_port.clone(),
+        virtual_port: if config.midi_mode == "virtual" { config.midi_virtual_port.clone() } else { String::new() },
+        channels: vec![config.midi_channel],
+    };
+    vec![legacy_input]
+}
+
+fn describe_channels(channels: &[u8]) -> String {
+    if channels.is_empty() {
+        return String::from("omni");
+    }
+    let channel_names: Vec<String> = channels.iter().map(|channel| channel.to_string()).collect();
+    format!("channels {}", channel_names.join(", "))
+}
+
+fn learn_message(message: &MidiMessage, program_changes: bool) -> Option<LearnedMessage> {
+    let learned_type = match message.status & 0xF0 {
+        NOTE_ON if message.data2.unwrap_or(0) > 0 => "note",
+        CONTROL_CHANGE if message.data2.unwrap_or(0) > 0 => "cc",
+        PROGRAMM_CHANGE if program_changes => "pc",
+        _ => return None,
+    };
+    Some(LearnedMessage {
+        message: learned_type,
+        channel: (message.status & 0x0F) + 1,
+        number: message.data1,
+    })
+}
+
+fn forward_midi_message(sender: &Sender<MidiMessage>, message: &[u8], channels: &[u8]) {
+    let parsed_message = parse_midi_message(message);
+    if let Some(mut payload) = parsed_message {
+        // System messages have no channel and are always passed on
+        payload.on_input_channel = payload.status >= 0xF0 || channels.is_empty() || channels.contains(&((payload.status & 0x0F) + 1));
+        trace!("MIDI Message: s {} - d1 {} - d2 {:?}", payload.status, payload.data1, payload.data2);
+        match sender.try_send(payload) {
+            Ok(()) => (),
+            Err(err) => error!("{}", err),
+        };
+    }
+}
+
 fn parse_midi_message(midi_message: &[u8]) -> Option<MidiMessage> {
     let parsed_midi_message = midi_message.to_vec();
+    if parsed_midi_message.len() == 1 && parsed_midi_message[0] >= TIMING_CLOCK {
+        // system real time messages consist of the status byte only
+        return Some(MidiMessage {
+            status: parsed_midi_message[0],
+            data1: 0,
+            data2: None,
+            time: Instant::now(),
+            on_input_channel: true,
+            sysex: Vec::new(),
+        });
+    }
     if parsed_midi_message.len() >=2 {
         let mut result = MidiMessage {
             status: parsed_midi_message[0],
             data1: parsed_midi_message[1],
             data2: None,
+            time: Instant::now(),
+            on_input_channel: true,
+            sysex: Vec::new(),
         };
         if parsed_midi_message.len() >= 3 {
             result.data2 = Some(parsed_midi_message[2])
         }
+        // System exclusive messages like full frame timecodes are kept as a whole
+        if result.status == SYSTEM_EXCLUSIVE {
+            result.sysex = parsed_midi_message;
+        }
         return Some(result);
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn control_change(number: u8, value: u8) -> MidiMessage {
+        MidiMessage { status: CONTROL_CHANGE, data1: number, data2: Some(value), time: Instant::now(), on_input_channel: true, sysex: Vec::new() }
+    }
+
+    fn port_with_14bit_tempo() -> (MidiPort, Sender<MidiMessage>) {
+        let mut config = BaseConfig::default();
+        config.midi_mode = String::from("none");
+        config.midi_tempo_mode = String::from("14bit");
+        let mut port = new(&config).unwrap();
+        let (sender, receiver) = unbounded();
+        port.receiver = Some(receiver);
+        (port, sender)
+    }
+
+    fn input(port: &str, virtual_port: &str, channels: Vec<u8>) -> MidiInputPort {
+        MidiInputPort { port: String::from(port), virtual_port: String::from(virtual_port), channels }
+    }
+
+    fn config_with_inputs(inputs: Vec<MidiInputPort>) -> BaseConfig {
+        let mut config = BaseConfig::default();
+        config.midi_mode = String::from("port");
+        config.midi_inputs = inputs;
+        config
+    }
+
+    #[test]
+    fn inputs_with_invalid_channels_are_rejected() {
+        assert!(new(&config_with_inputs(vec![input("", "Rustylight", vec![0])])).is_none());
+        assert!(new(&config_with_inputs(vec![input("", "Rustylight", vec![1, 17])])).is_none());
+    }
+
+    #[test]
+    fn inputs_need_a_port_name() {
+        assert!(new(&config_with_inputs(vec![input("", "", vec![1])])).is_none());
+    }
+
+    #[test]
+    fn duplicate_inputs_are_rejected() {
+        assert!(new(&config_with_inputs(vec![input("", "Rustylight", vec![1]), input("", "Rustylight", vec![2])])).is_none());
+        assert!(new(&config_with_inputs(vec![input("Uno", "", vec![1]), input("Uno", "", vec![])])).is_none());
+    }
+
+    #[test]
+    fn unknown_ports_are_rejected() {
+        assert!(new(&config_with_inputs(vec![input("No such midi port", "", vec![1])])).is_none());
+    }
+
+    #[test]
+    fn tempo_msb_waits_for_its_lsb_across_frames() {
+        let (mut port, sender) = port_with_14bit_tempo();
+        sender.send(control_change(12, 9)).unwrap();
+        assert_eq!(port.read_all().tempo, None);
+        sender.send(control_change(44, 123)).unwrap();
+        assert_eq!(port.read_all().tempo, Some(127.5));
+        // An LSB alone refines the last MSB
+        sender.send(control_change(44, 124)).unwrap();
+        assert_eq!(port.read_all().tempo, Some(127.6));
+    }
+
+    #[test]
+    fn tempo_msb_and_lsb_in_one_frame() {
+        let (mut port, sender) = port_with_14bit_tempo();
+        sender.send(control_change(44, 5)).unwrap();
+        sender.send(control_change(12, 10)).unwrap();
+        sender.send(control_change(44, 0)).unwrap();
+        assert_eq!(port.read_all().tempo, Some(128.0));
+    }
+}
@@ -0,0 +1,167 @@
+use std::time::{Duration, Instant};
+use log::{debug};
+
+const QUARTER_FRAME_PIECES: usize = 8; // a timecode is spread over 8 quarter frames, i.e. two frames
+const FRAME_RATES: [f64; 4] = [24.0, 25.0, 29.97, 30.0];
+const MAX_QUARTER_FRAME_INTERVAL: Duration = Duration::from_millis(250); // longer gaps mean playback stopped
+const FULL_FRAME_HEADER: [u8; 5] = [0xF0, 0x7F, 0x7F, 0x01, 0x01]; // the device id at index 2 is not checked
+const FULL_FRAME_SIZE: usize = 10;
+
+// Follows MIDI timecode. Quarter frames mean the DAW is playing and the position is interpolated between them,
+// full frames are sent when the DAW locates to a new position while stopped.
+pub struct MtcReader {
+    pieces: [u8; QUARTER_FRAME_PIECES],
+    received_pieces: u8,
+    position: f64,
+    position_time: Instant,
+    last_quarter_frame: Option<Instant>,
+    running: bool,
+    located: bool,
+}
+
+impl MtcReader {
+    pub fn new() -> MtcReader {
+        MtcReader {
+            pieces: [0; QUARTER_FRAME_PIECES],
+            received_pieces: 0,
+            position: 0.0,
+            position_time: Instant::now(),
+            last_quarter_frame: None,
+            running: false,
+            located: false,
+        }
+    }
+
+    pub fn quarter_frame(&mut self, data: u8, time: Instant) {
+        let piece = ((data >> 4) & 0x07) as usize;
+        self.pieces[piece] = data & 0x0F;
+        self.last_quarter_frame = Some(time);
+        // A timecode is complete once all pieces from frames low to hours high were received in order
+        if piece == 0 {
+            self.received_pieces = 0;
+        }
+        self.received_pieces |= 1 << piece;
+        if piece != QUARTER_FRAME_PIECES - 1 || self.received_pieces != 0xFF {
+            return;
+        }
+        let frames = self.pieces[0] | self.pieces[1] << 4;
+        let seconds = self.pieces[2] | self.pieces[3] << 4;
+        let minutes = self.pieces[4] | self.pieces[5] << 4;
+        let hours_and_rate = self.pieces[6] | self.pieces[7] << 4;
+        // The timecode refers to the first piece, which was sent two frames ago
+        let frame_rate = frame_rate(hours_and_rate);
+        self.position = timecode_seconds(hours_and_rate, minutes, seconds, frames) + 2.0 / frame_rate;
+        self.position_time = time;
+        if !self.running {
+            debug!("MIDI timecode: running at {:.3}s", self.position);
+            self.running = true;
+        }
+    }
+
+    pub fn full_frame(&mut self, message: &[u8], time: Instant) {
+        if message.len() != FULL_FRAME_SIZE || message[0..2] != FULL_FRAME_HEADER[0..2] || message[3..5] != FULL_FRAME_HEADER[3..5] {
+            return;
+        }
+        self.position = timecode_seconds(message[5], message[6], message[7], message[8]);
+        self.position_time = time;
+        self.received_pieces = 0;
+        self.running = false;
+        self.located = true;
+        debug!("MIDI timecode: located to {:.3}s", self.position);
+    }
+
+    // Returns the current position in seconds while playing or right after a locate
+    pub fn take_position(&mut self, time: Instant) -> Option<f64> {
+        if self.running && self.last_quarter_frame.map_or(true, |last| time.saturating_duration_since(last) > MAX_QUARTER_FRAME_INTERVAL) {
+            debug!("MIDI timecode: stopped at {:.3}s", self.position);
+            self.running = false;
+            self.received_pieces = 0;
+        }
+        if self.running {
+            return Some(self.position + time.saturating_duration_since(self.position_time).as_secs_f64());
+        }
+        if self.located {
+            self.located = false;
+            return Some(self.position);
+        }
+        None
+    }
+}
+
+fn frame_rate(hours_and_rate: u8) -> f64 {
+    FRAME_RATES[((hours_and_rate >> 5) & 0x03) as usize]
+}
+
+// Hours carry the frame rate in bit 5 and 6
+fn timecode_seconds(hours_and_rate: u8, minutes: u8, seconds: u8, frames: u8) -> f64 {
+    let hours = (hours_and_rate & 0x1F) as f64;
+    hours * 3600.0 + minutes as f64 * 60.0 + seconds as f64 + frames as f64 / frame_rate(hours_and_rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 01:02:03:04 at 25 fps, the rate code 1 sits in bit 5 and 6 of the hours
+    const QUARTER_FRAMES: [u8; 8] = [0x04, 0x10, 0x23, 0x30, 0x42, 0x50, 0x61, 0x72];
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.unwrap();
+        assert!((actual - expected).abs() < 1e-6, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn quarter_frames_give_the_position_two_frames_later() {
+        let mut reader = MtcReader::new();
+        let time = Instant::now();
+        for data in QUARTER_FRAMES {
+            reader.quarter_frame(data, time);
+        }
+        assert_close(reader.take_position(time), 3723.0 + 6.0 / 25.0);
+        assert_close(reader.take_position(time + Duration::from_millis(100)), 3723.1 + 6.0 / 25.0);
+    }
+
+    #[test]
+    fn incomplete_quarter_frames_are_ignored() {
+        let mut reader = MtcReader::new();
+        let time = Instant::now();
+        for data in &QUARTER_FRAMES[4..] {
+            reader.quarter_frame(*data, time);
+        }
+        assert_eq!(reader.take_position(time), None);
+        for data in &QUARTER_FRAMES[..7] {
+            reader.quarter_frame(*data, time);
+        }
+        assert_eq!(reader.take_position(time), None);
+    }
+
+    #[test]
+    fn playback_stops_without_quarter_frames() {
+        let mut reader = MtcReader::new();
+        let time = Instant::now();
+        for data in QUARTER_FRAMES {
+            reader.quarter_frame(data, time);
+        }
+        assert!(reader.take_position(time + MAX_QUARTER_FRAME_INTERVAL).is_some());
+        assert_eq!(reader.take_position(time + MAX_QUARTER_FRAME_INTERVAL + Duration::from_millis(1)), None);
+    }
+
+    #[test]
+    fn full_frame_locates_once() {
+        let mut reader = MtcReader::new();
+        let time = Instant::now();
+        // 01:02:03:15 at 30 fps
+        reader.full_frame(&[0xF0, 0x7F, 0x7F, 0x01, 0x01, 0x61, 0x02, 0x03, 0x0F, 0xF7], time);
+        assert_close(reader.take_position(time), 3723.5);
+        assert_eq!(reader.take_position(time), None);
+    }
+
+    #[test]
+    fn other_sysex_is_no_full_frame() {
+        let mut reader = MtcReader::new();
+        let time = Instant::now();
+        reader.full_frame(&[0xF0, 0x7F, 0x7F, 0x01, 0x02, 0x61, 0x02, 0x03, 0x0F, 0xF7], time);
+        reader.full_frame(&[0xF0, 0x7F, 0x7F, 0x01, 0x01, 0x61, 0x02, 0xF7], time);
+        assert_eq!(reader.take_position(time), None);
+    }
+}
@@ -0,0 +1,359 @@
+use derive_more::Display;
+use std::error::Error as StdError;
+use std::net::{ToSocketAddrs, SocketAddr, Ipv4Addr, UdpSocket};
+use std::cmp::{ min };
+use rand::Rng;
+use log::{info, debug, error};
+use crate::configuration::BaseConfig;
+use crate::dmx_outputs::{DmxOutput, frame_for};
+use crate::shows::DmxUniverses;
+
+const ACN_PACKET_IDENTIFIER: &[u8; 12] = b"ASC-E1.17\0\0\0";
+const VECTOR_ROOT_E131_DATA: u32 = 0x00000004;
+const VECTOR_E131_DATA_PACKET: u32 = 0x00000002;
+const VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+const DMP_ADDRESS_AND_DATA_TYPE: u8 = 0xA1;
+const STREAM_TERMINATED_OPTION: u8 = 0x40;
+const DEFAULT_PORT: u16 = 5568;
+
+const SOURCE_NAME_SIZE: usize = 64;
+const HEADER_SIZE: usize = 126; // root, framing and dmp layer up to and including the start code
+const MAX_FRAME_SIZE: usize = 512;
+const MIN_UNIVERSE: u16 = 1;
+const MAX_UNIVERSE: u16 = 63999;
+const MAX_PRIORITY: u8 = 200;
+const TERMINATION_PACKETS: usize = 3; // E1.31 requires the terminated flag to be sent three times
+
+
+#[derive(Debug, Display)]
+pub enum Error {
+    IO(std::io::Error),
+    InvalidDestination(String),
+    InvalidUniverse(u16),
+    PortClosed,
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::IO(e)
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        use Error::*;
+        match *self {
+            IO(ref e) => Some(e),
+            InvalidDestination(_) => None,
+            InvalidUniverse(_) => None,
+            PortClosed => None,
+        }
+    }
+}
+
+pub struct Sacn {
+    socket: Option<UdpSocket>,
+    destination: String,
+    destination_address: Option<SocketAddr>,
+    show_universe: u16,
+    universe: u16,
+    priority: u8,
+    source_name: String,
+    cid: [u8; 16],
+    sequence: u8,
+}
+
+impl Sacn {
+    pub fn new(destination_input: &str, show_universe: u16, universe: u16, priority: u8, source_name: &str, cid: [u8; 16]) -> Sacn {
+        Sacn {
+            socket: None,
+            destination: String::from(destination_input),
+            destination_address: None,
+            show_universe,
+            universe,
+            priority: min(priority, MAX_PRIORITY),
+            source_name: String::from(source_name),
+            cid,
+            sequence: 0,
+        }
+    }
+
+    pub fn open(&mut self) -> Result<(), Error> {
+        if self.socket.is_some() {
+            return Ok(());
+        }
+        if !(MIN_UNIVERSE..=MAX_UNIVERSE).contains(&self.universe) {
+            return Err(Error::InvalidUniverse(self.universe));
+        }
+
+        let destination_address = resolve_destination(&self.destination, self.universe)?;
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.set_nonblocking(true)?;
+
+        self.destination_address = Some(destination_address);
+        self.socket = Some(socket);
+        Ok(())
+    }
+
+    fn build_packet(&mut self, frame: &[u8], options: u8) -> Vec<u8> {
+        let slot_count = min(frame.len(), MAX_FRAME_SIZE);
+        let packet_size = HEADER_SIZE + slot_count;
+        let mut packet = Vec::with_capacity(packet_size);
+
+        // Root layer
+        packet.extend_from_slice(&0x0010u16.to_be_bytes()); // preamble size
+        packet.extend_from_slice(&0x0000u16.to_be_bytes()); // postamble size
+        packet.extend_from_slice(ACN_PACKET_IDENTIFIER);
+        packet.extend_from_slice(&flags_and_length(packet_size - 16));
+        packet.extend_from_slice(&VECTOR_ROOT_E131_DATA.to_be_bytes());
+        packet.extend_from_slice(&self.cid);
+
+        // Framing layer
+        packet.extend_from_slice(&flags_and_length(packet_size - 38));
+        packet.extend_from_slice(&VECTOR_E131_DATA_PACKET.to_be_bytes());
+        let mut source_name = [0; SOURCE_NAME_SIZE];
+        let name_bytes = self.source_name.as_bytes();
+        let name_size = min(name_bytes.len(), SOURCE_NAME_SIZE - 1); // always keep the null terminator
+        source_name[0..name_size].copy_from_slice(&name_bytes[0..name_size]);
+        packet.extend_from_slice(&source_name);
+        packet.push(self.priority);
+        packet.extend_from_slice(&0u16.to_be_bytes()); // synchronization address, not used
+        packet.push(self.sequence);
+        packet.push(options);
+        packet.extend_from_slice(&self.universe.to_be_bytes());
+
+        // DMP layer
+        packet.extend_from_slice(&flags_and_length(packet_size - 115));
+        packet.push(VECTOR_DMP_SET_PROPERTY);
+        packet.push(DMP_ADDRESS_AND_DATA_TYPE);
+        packet.extend_from_slice(&0u16.to_be_bytes()); // first property address
+        packet.extend_from_slice(&1u16.to_be_bytes()); // address increment
+        packet.extend_from_slice(&((slot_count + 1) as u16).to_be_bytes()); // slots plus start code
+        packet.push(0); // DMX start code
+        packet.extend_from_slice(&frame[0..slot_count]);
+
+        self.sequence = self.sequence.wrapping_add(1);
+        packet
+    }
+
+    fn write_packet(&mut self, packet: &[u8]) -> Result<(), Error> {
+        let socket = self.socket.as_ref().ok_or(Error::PortClosed)?;
+        let destination_address = self.destination_address.ok_or(Error::PortClosed)?;
+        socket.send_to(packet, destination_address)?;
+        Ok(())
+    }
+
+    pub fn write_frame(&mut self, frame: &[u8]) {
+        if frame.len() > MAX_FRAME_SIZE {
+            debug!("Frame data too large, cutting off excess data");
+        }
+        let packet = self.build_packet(frame, 0);
+        let written = self.write_packet(&packet);
+        match written {
+            Ok(()) => (),
+            Err(error) => error!("Frame was not successfully sent via sACN: {:?}", error)
+        }
+    }
+}
+
+impl DmxOutput for Sacn {
+    fn write(&mut self, universes: &DmxUniverses) {
+        self.write_frame(frame_for(universes, self.show_universe));
+    }
+
+    fn close(&mut self) {
+        if self.socket.is_none() {
+            return;
+        }
+        // Let receivers know right away that this source is gone instead of waiting for the data loss timeout
+        for _ in 0..TERMINATION_PACKETS {
+            let packet = self.build_packet(&[0; MAX_FRAME_SIZE], STREAM_TERMINATED_OPTION);
+            if let Err(error) = self.write_packet(&packet) {
+                error!("Stream termination was not successfully sent via sACN: {:?}", error);
+                break;
+            }
+        }
+        self.socket = None;
+    }
+}
+
+fn flags_and_length(length: usize) -> [u8; 2] {
+    (0x7000 | (length as u16 & 0x0FFF)).to_be_bytes()
+}
+
+fn resolve_destination(destination: &str, universe: u16) -> Result<SocketAddr, Error> {
+    // Without a destination the universe's multicast group 239.255.<universe hi>.<universe lo> is used
+    if destination.is_empty() {
+        let [universe_hi, universe_lo] = universe.to_be_bytes();
+        return Ok(SocketAddr::from((Ipv4Addr::new(239, 255, universe_hi, universe_lo), DEFAULT_PORT)));
+    }
+    let resolved = if destination.contains(':') {
+        destination.to_socket_addrs()
+    } else {
+        (destination, DEFAULT_PORT).to_socket_addrs()
+    };
+    match resolved {
+        Ok(mut addresses) => addresses.next().ok_or(Error::InvalidDestination(String::from(destination))),
+        Err(_) => Err(Error::InvalidDestination(String::from(destination))),
+    }
+}
+
+fn parse_cid(cid_input: &str) -> Option<[u8; 16]> {
+    let hex: String = cid_input.chars().filter(|c| *c != '-').collect();
+    if hex.len() != 32 {
+        return None;
+    }
+    let mut cid = [0; 16];
+    for (i, byte) in cid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(cid)
+}
+
+fn generate_cid() -> [u8; 16] {
+    let mut cid: [u8; 16] = rand::thread_rng().gen();
+    // Mark as random UUID (version 4, RFC 4122 variant)
+    cid[6] = (cid[6] & 0x0F) | 0x40;
+    cid[8] = (cid[8] & 0x3F) | 0x80;
+    cid
+}
+
+fn format_cid(cid: &[u8; 16]) -> String {
+    let hex: Vec<String> = cid.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}-{}-{}-{}", hex[0..4].concat(), hex[4..6].concat(), hex[6..8].concat(), hex[8..10].concat(), hex[10..16].concat())
+}
+
+pub fn open_sacn_ports(config: &BaseConfig) -> Result<Vec<Sacn>, Box<dyn StdError>> {
+    // All universes are sent by the same source and therefore share one CID
+    let cid = if config.sacn_cid.is_empty() {
+        let cid = generate_cid();
+        info!("Generated sACN CID:      {} (set sacn_cid in your config to keep it)", format_cid(&cid));
+        cid
+    } else if let Some(cid) = parse_cid(&config.sacn_cid) {
+        cid
+    } else {
+        error!("");
+        error!("!!  Invalid sacn_cid '{}', expected a UUID like '6ba7b810-9dad-11d1-80b4-00c04fd430c8'.  !!", config.sacn_cid);
+        error!("");
+        return Err("".into());
+    };
+    let mut ports = Vec::new();
+    for show_universe in config.sacn_universes.iter() {
+        // The configured sACN universe is used for the first show universe, following ones count up from there
+        let universe = if *show_universe >= 1 {
+            config.sacn_universe.saturating_add(show_universe - 1)
+        } else {
+            0
+        };
+        let mut sacn = Sacn::new(
+            &config.sacn_destination,
+            *show_universe,
+            universe,
+            config.sacn_priority,
+            &config.sacn_source_name,
+            cid,
+        );
+        let opened = sacn.open();
+        if let Err(error) = opened {
+            error!("");
+            error!("!!  Couldn't open sACN output for universe {}: {:?}  !!", show_universe, error);
+            error!("    Check sacn_destination, sacn_universe and sacn_universes ({}-{}) in your config.", MIN_UNIVERSE, MAX_UNIVERSE);
+            error!("");
+            return Err("".into());
+        }
+        let destination = if config.sacn_destination.is_empty() { "multicast" } else { &config.sacn_destination };
+        info!("Opened sACN output:      {} (universe {} -> {}, priority {})", destination, show_universe, universe, sacn.priority);
+        ports.push(sacn);
+    }
+    Ok(ports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const CID: [u8; 16] = [0x11; 16];
+
+    fn receive(socket: &UdpSocket) -> Vec<u8> {
+        let mut buffer = [0; 1024];
+        let (size, _) = socket.recv_from(&mut buffer).unwrap();
+        buffer[..size].to_vec()
+    }
+
+    fn length(packet: &[u8], offset: usize) -> usize {
+        (u16::from_be_bytes([packet[offset], packet[offset + 1]]) & 0x0FFF) as usize
+    }
+
+    #[test]
+    fn sends_e131_data_to_the_destination() {
+        let receiver = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let destination = receiver.local_addr().unwrap().to_string();
+        let mut sacn = Sacn::new(&destination, 1, 7, 100, "Rustylight", CID);
+        sacn.open().unwrap();
+
+        sacn.write_frame(&[10, 20, 30]);
+        let packet = receive(&receiver);
+        assert_eq!(packet.len(), HEADER_SIZE + 3);
+        // Root layer
+        assert_eq!(packet[0..4], [0x00, 0x10, 0x00, 0x00]);
+        assert_eq!(&packet[4..16], ACN_PACKET_IDENTIFIER);
+        assert_eq!(packet[16] & 0xF0, 0x70);
+        assert_eq!(length(&packet, 16), packet.len() - 16);
+        assert_eq!(packet[18..22], VECTOR_ROOT_E131_DATA.to_be_bytes());
+        assert_eq!(packet[22..38], CID);
+        // Framing layer
+        assert_eq!(length(&packet, 38), packet.len() - 38);
+        assert_eq!(packet[40..44], VECTOR_E131_DATA_PACKET.to_be_bytes());
+        assert_eq!(&packet[44..54], b"Rustylight");
+        assert_eq!(packet[54..108], [0; 54]);
+        assert_eq!(packet[108], 100); // priority
+        assert_eq!(packet[111], 0); // sequence
+        assert_eq!(packet[112], 0); // options
+        assert_eq!(packet[113..115], [0, 7]); // universe
+        // DMP layer
+        assert_eq!(length(&packet, 115), packet.len() - 115);
+        assert_eq!(packet[117..119], [VECTOR_DMP_SET_PROPERTY, DMP_ADDRESS_AND_DATA_TYPE]);
+        assert_eq!(packet[119..123], [0, 0, 0, 1]);
+        assert_eq!(packet[123..125], [0, 4]); // slots plus start code
+        assert_eq!(packet[125..], [0, 10, 20, 30]);
+
+        sacn.write_frame(&[0; 600]);
+        let packet = receive(&receiver);
+        assert_eq!(packet[111], 1);
+        assert_eq!(packet.len(), HEADER_SIZE + MAX_FRAME_SIZE);
+    }
+
+    #[test]
+    fn close_terminates_the_stream() {
+        let receiver = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let destination = receiver.local_addr().unwrap().to_string();
+        let mut sacn = Sacn::new(&destination, 1, 1, 250, "", CID);
+        sacn.open().unwrap();
+        sacn.close();
+        for sequence in 0..TERMINATION_PACKETS {
+            let packet = receive(&receiver);
+            assert_eq!(packet[108], MAX_PRIORITY);
+            assert_eq!(packet[111], sequence as u8);
+            assert_eq!(packet[112], STREAM_TERMINATED_OPTION);
+        }
+        assert!(sacn.socket.is_none());
+    }
+
+    #[test]
+    fn default_destination_is_the_universe_multicast_group() {
+        let address = resolve_destination("", 0x0102).unwrap();
+        assert_eq!(address, SocketAddr::from((Ipv4Addr::new(239, 255, 1, 2), DEFAULT_PORT)));
+        assert_eq!(resolve_destination("127.0.0.1", 1).unwrap().port(), DEFAULT_PORT);
+    }
+
+    #[test]
+    fn cid_round_trips() {
+        let cid = generate_cid();
+        assert_eq!(cid[6] & 0xF0, 0x40);
+        assert_eq!(parse_cid(&format_cid(&cid)), Some(cid));
+        assert_eq!(parse_cid("0123"), None);
+    }
+}
@@ -1,71 +1,129 @@
 use crate::configuration::BaseConfig;
-use crate::faders::{Fader, fader_from_mapping};
+use crate::faders::{Fader, fader_from_mapping, parse_address};
+use crate::tempo::{BeatGrid, QUANTIZE_SETTINGS, quantize_unit, previous_boundary, next_boundary};
 use std::time::Instant;
-use std::path::Path;
-use std::fs::{DirEntry, File};
-use serde_yaml::{from_reader, Mapping};
-use log::{debug, error};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::fs::{create_dir_all, remove_file, rename, DirEntry, File, OpenOptions};
+use std::io::ErrorKind;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_yaml::{from_reader, to_writer, Mapping, Value};
+use log::{debug, info, error};
 
-const DEFAULT_TEMPO: u8 = 120;
+const DEFAULT_TEMPO: f64 = 120.0;
+const MIN_TEMPO: f64 = 1.0; // a tempo of 0 would stretch movements endlessly
+const TIMELINE_FILE: &str = "timeline.yml";
+const DEFAULT_FRAME_RATE: f64 = 25.0;
+pub const UNIVERSE_SIZE: usize = 512;
+
+pub type DmxUniverses = BTreeMap<u16, [u8; UNIVERSE_SIZE]>;
+
+#[derive(Debug, PartialEq)]
+pub enum Transport {
+    Start,
+    Continue,
+    Stop,
+}
 
 pub struct ShowUpdate {
     pub song: Option<usize>,
     pub scene: Option<usize>,
-    pub tempo: Option<u8>,
+    pub scene_step: Option<isize>,
+    pub tempo: Option<f64>,
     pub off: Option<bool>,
+    pub capture: Option<bool>,
+    pub transport: Option<Transport>,
+    pub beat: Option<(u64, Instant)>,
+    pub timecode: Option<f64>,
+    pub controls: [Option<u8>; 128],
+    pub note_hits: [Option<u8>; 128],
     pub notes: [Option<u8>; 128],
 }
 
 pub struct Show {
     name: String,
     songs: Vec<Song>,
+    universes: Vec<u16>,
     selected_song: usize,
-    selected_tempo: u8,
+    selected_tempo: f64,
+    beat_grid: BeatGrid,
+    controls: [Option<u8>; 128],
     off: bool,
 }
 
 impl Show {
     pub fn update_state(&mut self, mut patch: ShowUpdate, config: &BaseConfig) {
-        if let Some(next_song) = patch.song {
-            if self.selected_song != next_song {
-                if self.songs.len() > next_song {
-                    self.selected_song = next_song;
-                    self.print_selected_song();
-                    self.songs[self.selected_song].reset();
-                }
-            }
-        }
+        let now = Instant::now();
 
         if let Some(next_tempo) = patch.tempo {
+            let next_tempo = next_tempo.max(MIN_TEMPO);
             if self.selected_tempo != next_tempo {
                 self.selected_tempo = next_tempo;
+                self.beat_grid.set_tempo(next_tempo, now);
                 debug!("Tempo: {}", self.selected_tempo);
             }
         } else {
             patch.tempo = Some(self.selected_tempo);
         }
 
+        // Keep the beat grid locked to the clock source
+        if let Some((beat, time)) = patch.beat {
+            self.align_beat_grid(beat, time);
+        }
+
+        // Movements restart with the downbeat when the clock source starts playing, a pending scene starts right away
+        if patch.transport == Some(Transport::Start) {
+            self.align_beat_grid(0, now);
+            if self.songs.len() > self.selected_song {
+                self.songs[self.selected_song].start(0.0);
+            }
+        }
+
+        if let Some(next_song) = patch.song {
+            if self.selected_song != next_song {
+                if self.songs.len() > next_song {
+                    self.selected_song = next_song;
+                    self.print_selected_song();
+                    let start_beat = aligned_start(self.beat_grid.position(now), config);
+                    self.songs[self.selected_song].reset(start_beat);
+                }
+            }
+        }
+
         if let Some(_) = patch.off {
             self.off = true;
-        } else if patch.song.is_some() || patch.scene.is_some() {
+        } else if patch.song.is_some() || patch.scene.is_some() || patch.scene_step.is_some() {
             self.off = false;
         }
 
+        // Controls keep their last value across songs and scenes, like the physical faders sending them
+        self.controls = merge_values(self.controls, patch.controls);
+        patch.controls = self.controls;
+
         if self.songs.len() > self.selected_song {
-            self.songs[self.selected_song].update_state(patch, &config);
+            self.songs[self.selected_song].update_state(patch, &config, &self.beat_grid);
         }
     }
 
-    pub fn get_dmx_data(&self) -> [u8; 255] {
-        if self.off {
-            [0; 255]
-        } else if self.songs.len() > self.selected_song {
-            self.songs[self.selected_song].get_dmx_data()
-        } else {
-            [0; 255]
+    // Beats of the running scene and a pending scene change are moved along with the grid,
+    // so they keep their distance to the current position after a locate
+    fn align_beat_grid(&mut self, beat: u64, time: Instant) {
+        let offset = beat as f64 - self.beat_grid.position(time);
+        self.beat_grid.align(beat, time);
+        if self.songs.len() > self.selected_song {
+            self.songs[self.selected_song].shift_beats(offset);
         }
     }
 
+    pub fn get_dmx_data(&self) -> DmxUniverses {
+        // Every universe used somewhere in the show is rendered so unused ones are blacked out
+        let mut dmx_data: DmxUniverses = self.universes.iter().map(|universe| (*universe, [0; UNIVERSE_SIZE])).collect();
+        if !self.off && self.songs.len() > self.selected_song {
+            dmx_data.extend(self.songs[self.selected_song].get_dmx_data());
+        }
+        dmx_data
+    }
+
     pub fn print_content(&self) {
         debug!("");
         debug!("{}", self.name);
@@ -76,6 +134,60 @@ impl Show {
         debug!("");
     }
 
+    pub fn get_selected_song(&self) -> usize {
+        self.selected_song
+    }
+
+    pub fn get_selected_scene(&self) -> Option<usize> {
+        self.songs.get(self.selected_song).map(|song| song.selected_scene)
+    }
+
+    pub fn get_beat_position(&self, time: Instant) -> f64 {
+        self.beat_grid.position(time)
+    }
+
+    // Writes a learned note or control into a fader of the current scene and reloads the scene.
+    // The fader is added if the scene doesn't contain it yet. The file is rewritten from the parsed yaml, so comments get lost.
+    pub fn learn_fader(&mut self, address: &Value, fader_type: &str, param: &str, number: u8) -> Result<String, String> {
+        let target = parse_address(address).ok_or("invalid fader address")?;
+        let song = self.songs.get_mut(self.selected_song).ok_or("no song selected")?;
+        let scene = song.scenes.get_mut(song.selected_scene).ok_or("no scene selected")?;
+
+        let scene_file = File::open(&scene.path).map_err(|err| err.to_string())?;
+        let mut yaml_data: Mapping = from_reader(scene_file).map_err(|err| err.to_string())?;
+        let faders_key = Value::from("faders");
+        if !yaml_data.contains_key(&faders_key) {
+            yaml_data.insert(faders_key.clone(), Value::from(Mapping::new()));
+        }
+        let faders = yaml_data.get_mut(&faders_key).and_then(|faders| faders.as_mapping_mut()).ok_or("faders is not a mapping")?;
+        let fader_key = faders.iter().map(|(key, _)| key).find(|key| parse_address(key) == Some(target)).cloned();
+        let fader_key = match fader_key {
+            Some(key) => key,
+            None => {
+                let mut properties = Mapping::new();
+                properties.insert(Value::from("value"), Value::from(255));
+                faders.insert(address.clone(), Value::from(properties));
+                address.clone()
+            }
+        };
+        // Existing entries are changed in place to keep the order of the file
+        let properties = faders.get_mut(&fader_key).and_then(|properties| properties.as_mapping_mut()).ok_or("fader properties are not a mapping")?;
+        properties.insert(Value::from("type"), Value::from(fader_type));
+        let params_key = Value::from("params");
+        if !properties.get(&params_key).is_some_and(|params| params.is_mapping()) {
+            properties.insert(params_key.clone(), Value::from(Mapping::new()));
+        }
+        let params = properties.get_mut(&params_key).and_then(|params| params.as_mapping_mut()).ok_or("params are not a mapping")?;
+        params.insert(Value::from(param), Value::from(number as u64));
+
+        write_scene_file(&scene.path, &yaml_data)?;
+
+        let mut learned_scene = load_scene_from_path(&scene.path).ok_or("the written scene couldn't be loaded")?;
+        learned_scene.start_beat = scene.start_beat;
+        *scene = learned_scene;
+        Ok(scene.path.display().to_string())
+    }
+
     pub fn print_selected_song(&self) {
         debug!("Song: {}. {}", self.selected_song, self.songs[self.selected_song].name);
     }
@@ -85,39 +197,110 @@ pub struct Song {
     name: String,
     scenes: Vec<Scene>,
     selected_scene: usize,
+    pending_scene: Option<(usize, f64)>,
+    timeline: Vec<Cue>,
+    active_cue: Option<usize>,
     notes: [Option<u8>; 128],
 }
 
+// Selects a scene at a timecode position in seconds
+struct Cue {
+    time: f64,
+    scene: usize,
+}
+
 impl Song {
-    pub fn reset(&mut self) {
+    pub fn reset(&mut self, start_beat: f64) {
         self.selected_scene = 0;
+        self.pending_scene = None;
+        self.active_cue = None;
+        self.restart_scene(start_beat);
         self.print_selected_scene()
     }
 
-    pub fn update_state(&mut self, mut patch: ShowUpdate, config: &BaseConfig) {
-        if let Some(next_scene) = patch.scene {
-            if self.selected_scene != next_scene {
-                if self.scenes.len() > next_scene {
-                    self.selected_scene = next_scene;
-                    self.scenes[self.selected_scene].reset();
-                    self.print_selected_scene();
+    fn restart_scene(&mut self, start_beat: f64) {
+        if self.scenes.len() > self.selected_scene {
+            self.scenes[self.selected_scene].reset(start_beat);
+        }
+    }
+
+    pub fn start(&mut self, start_beat: f64) {
+        match self.pending_scene.take() {
+            Some((next_scene, _)) => self.select_scene(next_scene, start_beat),
+            None => self.restart_scene(start_beat),
+        }
+    }
+
+    pub fn shift_beats(&mut self, offset: f64) {
+        if let Some((_, switch_beat)) = &mut self.pending_scene {
+            *switch_beat += offset;
+        }
+        if self.scenes.len() > self.selected_scene {
+            self.scenes[self.selected_scene].start_beat += offset;
+        }
+    }
+
+    fn select_scene(&mut self, next_scene: usize, start_beat: f64) {
+        self.selected_scene = next_scene;
+        self.scenes[self.selected_scene].reset(start_beat);
+        self.print_selected_scene();
+    }
+
+    pub fn update_state(&mut self, mut patch: ShowUpdate, config: &BaseConfig, beat_grid: &BeatGrid) {
+        let position = beat_grid.position(Instant::now());
+        // Steps count from a pending scene change, so pressing next twice skips a scene
+        let step_scene = patch.scene_step.filter(|_| !self.scenes.is_empty()).map(|step| {
+            let current_scene = self.pending_scene.map_or(self.selected_scene, |(scene, _)| scene) as isize;
+            (current_scene + step).clamp(0, self.scenes.len() as isize - 1) as usize
+        });
+        if let Some(next_scene) = patch.scene.or(step_scene) {
+            if self.selected_scene == next_scene {
+                self.pending_scene = None;
+            } else if self.scenes.len() > next_scene {
+                // Quantized scene changes wait for the next beat or bar
+                if let Some(unit) = quantize_unit(&config.scene_quantize, config.beats_per_bar) {
+                    self.pending_scene = Some((next_scene, next_boundary(position, unit)));
+                } else {
+                    self.select_scene(next_scene, aligned_start(position, config));
+                }
+            }
+        }
+        if let Some((next_scene, switch_beat)) = self.pending_scene {
+            if position >= switch_beat {
+                self.pending_scene = None;
+                self.select_scene(next_scene, switch_beat);
+            }
+        }
+
+        // The latest cue before the timecode is active, so jumps and locates end up in the right scene.
+        // Scenes selected by hand stay until the next cue.
+        if let Some(timecode) = patch.timecode {
+            let cue = self.timeline.iter().rposition(|cue| cue.time <= timecode);
+            if cue != self.active_cue {
+                self.active_cue = cue;
+                if let Some(cue) = cue {
+                    let next_scene = self.timeline[cue].scene;
+                    self.pending_scene = None;
+                    if self.selected_scene != next_scene {
+                        self.select_scene(next_scene, aligned_start(position, config));
+                    }
                 }
             }
         }
 
-        self.notes = merge_notes(self.notes, patch.notes);
+        self.notes = merge_values(self.notes, patch.notes);
         patch.notes = self.notes;
 
         if self.scenes.len() > self.selected_scene {
-            self.scenes[self.selected_scene].update_state(patch, &config);
+            self.scenes[self.selected_scene].update_state(patch, &config, beat_grid);
         }
     }
 
-    pub fn get_dmx_data(&self) -> [u8; 255] {
+    pub fn get_dmx_data(&self) -> DmxUniverses {
         if self.scenes.len() > self.selected_scene {
             self.scenes[self.selected_scene].get_dmx_data()
         } else {
-            [0; 255]
+            DmxUniverses::new()
         }
     }
     
@@ -135,64 +318,160 @@ impl Song {
 
 pub struct Scene {
     name: String,
-    start_time: Instant,
+    path: PathBuf,
+    start_beat: f64,
     faders: Vec<Fader>
 }
 
 impl Scene {
-    pub fn reset(&mut self) {
-        self.start_time = Instant::now();
+    pub fn reset(&mut self, start_beat: f64) {
+        self.start_beat = start_beat;
     }
 
-    pub fn update_state(&mut self, patch: ShowUpdate, config: &BaseConfig) {
+    pub fn update_state(&mut self, patch: ShowUpdate, config: &BaseConfig, beat_grid: &BeatGrid) {
         let current_tempo = patch.tempo.unwrap_or(DEFAULT_TEMPO);
+        // The scene start is kept in beats, so movements stay in phase with the beat grid
+        let start_time = beat_grid.time_of(self.start_beat);
         for fader in &mut self.faders {
-            fader.update_state(current_tempo, self.start_time, patch.notes, &config);
+            fader.update_state(current_tempo, start_time, patch.notes, patch.note_hits, patch.controls, &config);
         }
     }
 
-    pub fn get_dmx_data(&self) -> [u8; 255] {
-        let mut dmx_data = [0; 255];
+    pub fn get_dmx_data(&self) -> DmxUniverses {
+        let mut dmx_data = DmxUniverses::new();
         for fader in &self.faders {
-            dmx_data[fader.get_channel()] = fader.get_value();
+            let universe = dmx_data.entry(fader.get_universe()).or_insert([0; UNIVERSE_SIZE]);
+            // Channels are numbered from 1 like in fixture manuals and validated on load
+            universe[fader.get_channel() - 1] = fader.get_value();
         }
         return dmx_data;
     }
+
+    pub fn get_universes(&self) -> Vec<u16> {
+        self.faders.iter().map(|fader| fader.get_universe()).collect()
+    }
     
     pub fn print_content(&self, index: usize) {
         debug!("    {} {}", index, self.name);
     }
 }
 
-fn merge_notes(notes_old: [Option<u8>; 128], notes_new: [Option<u8>; 128]) -> [Option<u8>; 128] {
-    let mut notes_merge = notes_old;
-    for (index, note) in notes_new.iter().enumerate() {
-        if let Some(_) = note {
-            notes_merge[index] = *note;
+// Scenes start on the latest beat or bar if configured, otherwise right away
+fn aligned_start(position: f64, config: &BaseConfig) -> f64 {
+    match quantize_unit(&config.beat_align, config.beats_per_bar) {
+        Some(unit) => previous_boundary(position, unit),
+        None => position,
+    }
+}
+
+// Merges a received universe into the rendered one, the highest value of each channel wins
+pub fn merge_input(dmx_data: &mut DmxUniverses, universe: u16, input: &[u8; UNIVERSE_SIZE]) {
+    let frame = dmx_data.entry(universe).or_insert([0; UNIVERSE_SIZE]);
+    for (value, input_value) in frame.iter_mut().zip(input.iter()) {
+        if *input_value > *value {
+            *value = *input_value;
+        }
+    }
+}
+
+// Stores a received universe as new scene file, to be moved into a song afterwards
+pub fn capture_scene(capture_path: &str, universe: u16, input: &[u8; UNIVERSE_SIZE]) {
+    let mut faders = Mapping::new();
+    for (index, value) in input.iter().enumerate() {
+        if *value == 0 {
+            continue;
+        }
+        let channel = index + 1;
+        let address = if universe == 1 {
+            Value::from(channel as u64)
+        } else {
+            Value::from(format!("{}:{}", universe, channel))
+        };
+        let mut properties = Mapping::new();
+        properties.insert(Value::from("value"), Value::from(*value as u64));
+        faders.insert(address, Value::from(properties));
+    }
+
+    let written = create_dir_all(capture_path)
+        .and_then(|_| create_capture_file(capture_path))
+        .map_err(|err| err.to_string())
+        .and_then(|(name, scene_path, scene_file)| {
+            let mut scene = Mapping::new();
+            scene.insert(Value::from("01_name"), Value::from(name));
+            scene.insert(Value::from("faders"), Value::from(faders));
+            to_writer(scene_file, &scene).map(|_| scene_path).map_err(|err| err.to_string())
+        });
+    match written {
+        Ok(scene_path) => info!("Captured DMX input to:   {}", scene_path.display()),
+        Err(err) => error!("Couldn't capture DMX input to {}: {}", capture_path, err),
+    }
+}
+
+// Captures within the same second get a counter suffix, existing scene files are never overwritten
+fn create_capture_file(capture_path: &str) -> std::io::Result<(String, PathBuf, File)> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+    let mut counter = 1;
+    loop {
+        let name = if counter == 1 {
+            format!("captured_{}", timestamp)
+        } else {
+            format!("captured_{}_{}", timestamp, counter)
+        };
+        let scene_path = Path::new(capture_path).join(format!("{}.yml", name));
+        match OpenOptions::new().write(true).create_new(true).open(&scene_path) {
+            Ok(scene_file) => return Ok((name, scene_path, scene_file)),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => counter += 1,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// Notes and controls keep their last received value
+fn merge_values(values_old: [Option<u8>; 128], values_new: [Option<u8>; 128]) -> [Option<u8>; 128] {
+    let mut values_merge = values_old;
+    for (index, value) in values_new.iter().enumerate() {
+        if let Some(_) = value {
+            values_merge[index] = *value;
         }
     }
-    notes_merge
+    values_merge
 }
 
 pub fn load_show(config: &BaseConfig) -> Option<Show> {
+    for (setting, value) in [("beat_align", &config.beat_align), ("scene_quantize", &config.scene_quantize)] {
+        if !QUANTIZE_SETTINGS.contains(&value.as_str()) {
+            error!("");
+            error!("!!  Unknown {} '{}', use 'off', 'beat' or 'bar'.  !!", setting, value);
+            error!("");
+            return None;
+        }
+    }
     if !config.show_path.is_empty() {
         let show_path = Path::new(&config.show_path);
         if show_path.is_dir() {
             let mut show = Show {
                 name: String::from(show_path.file_name().unwrap().to_str().unwrap()),
                 songs: Vec::new(),
+                universes: vec![1],
                 selected_song: 0,
                 selected_tempo: DEFAULT_TEMPO,
+                beat_grid: BeatGrid::new(DEFAULT_TEMPO),
+                controls: [None; 128],
                 off: false,
             };
             let song_paths = get_ordered_subpaths_as_iter(show_path);
             for song_path in song_paths {
                 if song_path.path().is_dir() {
                     if let Some(song) = load_song_from_path(&song_path.path()) {
+                        for scene in &song.scenes {
+                            show.universes.extend(scene.get_universes());
+                        }
                         show.songs.push(song);
                     }
                 }
             }
+            show.universes.sort();
+            show.universes.dedup();
         return Some(show);
         }
     }
@@ -206,6 +485,9 @@ fn load_song_from_path(path: &Path) -> Option<Song> {
         name: String::from(path.file_name().unwrap().to_str().unwrap()),
         scenes: Vec::new(),
         selected_scene: 0,
+        pending_scene: None,
+        timeline: Vec::new(),
+        active_cue: None,
         notes: [None; 128],
     };
     let paths = get_ordered_subpaths_as_iter(path);
@@ -213,10 +495,17 @@ fn load_song_from_path(path: &Path) -> Option<Song> {
         if subpath.path().is_file() &&
             subpath.path().extension().is_some() &&
             subpath.path().extension().unwrap().eq("yml") &&
-            !subpath.file_name().to_str().unwrap().starts_with(".") {
-            song.scenes.push(load_scene_from_path(&subpath.path()));
+            !subpath.file_name().to_str().unwrap().starts_with(".") &&
+            subpath.file_name() != TIMELINE_FILE {
+            if let Some(scene) = load_scene_from_path(&subpath.path()) {
+                song.scenes.push(scene);
+            }
         }
     }
+    let timeline_path = path.join(TIMELINE_FILE);
+    if timeline_path.is_file() {
+        song.timeline = load_timeline_from_path(&timeline_path, &song.scenes);
+    }
     if song.scenes.len() > 0 {
         Some(song)
     } else {
@@ -224,12 +513,17 @@ fn load_song_from_path(path: &Path) -> Option<Song> {
     }
 }
 
-fn load_scene_from_path(path: &Path) -> Scene {
-    let scene_file = File::open(&path).unwrap();
-    let yaml_data: Mapping = from_reader(scene_file).unwrap();
+fn load_scene_from_path(path: &Path) -> Option<Scene> {
+    let yaml_data: Option<Mapping> = File::open(path).ok().and_then(|file| from_reader(file).ok());
+    if yaml_data.is_none() {
+        error!("!!  Scene '{}' couldn't be read and will be ignored  !!", path.display());
+        return None;
+    }
+    let yaml_data = yaml_data.unwrap();
     let mut scene = Scene {
         name: String::from(path.file_stem().unwrap().to_str().unwrap()),
-        start_time: Instant::now(),
+        path: path.to_path_buf(),
+        start_beat: 0.0,
         faders: Vec::new(),
     };
 
@@ -239,13 +533,78 @@ fn load_scene_from_path(path: &Path) -> Scene {
         } else if key.is_string() && key.eq("faders") && value.is_mapping() {
             for (channel, properties) in value.as_mapping().unwrap().iter() {
                 if let Some(fader) = fader_from_mapping(channel, properties) {
-                    scene.faders.push(fader);
+                    if fader.get_universe() < 1 {
+                        error!("!!  Universe {} in scene '{}' is invalid, universes are numbered from 1. The fader will be ignored  !!", fader.get_universe(), path.display());
+                    } else if fader.get_channel() >= 1 && fader.get_channel() <= UNIVERSE_SIZE {
+                        scene.faders.push(fader);
+                    } else {
+                        error!("!!  Channel {} in scene '{}' is out of range 1-{} and will be ignored  !!", fader.get_channel(), path.display(), UNIVERSE_SIZE);
+                    }
+                } else {
+                    error!("!!  Channel {:?} in scene '{}' is not a valid channel number and will be ignored  !!", channel, path.display());
                 }
             }
         }
     }
 
-    scene
+    Some(scene)
+}
+
+// The scene is written to a hidden file next to it first and then moved into place,
+// so a failing write doesn't leave a truncated scene behind
+fn write_scene_file(path: &Path, yaml_data: &Mapping) -> Result<(), String> {
+    let file_name = path.file_name().and_then(|name| name.to_str()).ok_or("invalid scene path")?;
+    let temp_path = path.with_file_name(format!(".{}.tmp", file_name));
+    let written = File::create(&temp_path)
+        .map_err(|err| err.to_string())
+        .and_then(|file| to_writer(file, yaml_data).map_err(|err| err.to_string()))
+        .and_then(|_| rename(&temp_path, path).map_err(|err| err.to_string()));
+    if written.is_err() {
+        let _ = remove_file(&temp_path);
+    }
+    written
+}
+
+// Cues point to scenes by index or name and their time is given as 'hh:mm:ss:ff' or in seconds
+fn load_timeline_from_path(path: &Path, scenes: &[Scene]) -> Vec<Cue> {
+    let mut timeline = Vec::new();
+    let timeline_data: Option<Mapping> = File::open(path).ok().and_then(|file| from_reader(file).ok());
+    if timeline_data.is_none() {
+        error!("!!  Timeline '{}' couldn't be read and will be ignored  !!", path.display());
+        return timeline;
+    }
+    let timeline_data = timeline_data.unwrap();
+    let frame_rate = timeline_data.get(&Value::from("frame_rate")).and_then(|rate| rate.as_f64()).unwrap_or(DEFAULT_FRAME_RATE);
+    let cues = timeline_data.get(&Value::from("cues")).and_then(|cues| cues.as_sequence());
+    for cue in cues.into_iter().flatten() {
+        let time = cue.get("time").and_then(|time| parse_timecode(time, frame_rate));
+        let scene = cue.get("scene").and_then(|scene| {
+            if let Some(index) = scene.as_u64() {
+                Some(index as usize).filter(|index| *index < scenes.len())
+            } else {
+                scenes.iter().position(|candidate| Some(candidate.name.as_str()) == scene.as_str())
+            }
+        });
+        match (time, scene) {
+            (Some(time), Some(scene)) => timeline.push(Cue { time, scene }),
+            _ => error!("!!  Cue {:?} in timeline '{}' has no valid time or scene and will be ignored  !!", cue, path.display()),
+        }
+    }
+    timeline.sort_by(|a, b| a.time.total_cmp(&b.time));
+    info!("Loaded timeline:         {} ({} cues)", path.display(), timeline.len());
+    timeline
+}
+
+fn parse_timecode(time: &Value, frame_rate: f64) -> Option<f64> {
+    if let Some(seconds) = time.as_f64() {
+        return Some(seconds);
+    }
+    let parts: Vec<f64> = time.as_str()?.split(':').map(|part| part.trim().parse().ok()).collect::<Option<_>>()?;
+    match parts.as_slice() {
+        [hours, minutes, seconds, frames] => Some(hours * 3600.0 + minutes * 60.0 + seconds + frames / frame_rate),
+        [hours, minutes, seconds] => Some(hours * 3600.0 + minutes * 60.0 + seconds),
+        _ => None,
+    }
 }
 
 fn get_ordered_subpaths_as_iter(path: &Path) -> Vec<DirEntry> {
@@ -256,5 +615,165 @@ fn get_ordered_subpaths_as_iter(path: &Path) -> Vec<DirEntry> {
                     .collect();
     paths
         .sort_by_key(|dir| dir.path());
-    return paths;
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read_dir, remove_dir_all, write};
+
+    fn scene_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustylight-{}-{}", name, std::process::id()));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn update() -> ShowUpdate {
+        ShowUpdate {
+            song: None,
+            scene: None,
+            scene_step: None,
+            tempo: None,
+            off: None,
+            capture: None,
+            transport: None,
+            beat: None,
+            timecode: None,
+            controls: [None; 128],
+            note_hits: [None; 128],
+            notes: [None; 128],
+        }
+    }
+
+    fn scene(name: &str) -> Scene {
+        Scene { name: String::from(name), path: PathBuf::from(name), start_beat: 0.0, faders: Vec::new() }
+    }
+
+    // A song with two scenes, played at beat 1001 where a quantized change to the second scene waits for beat 1004
+    fn show_with_pending_scene(config: &BaseConfig) -> Show {
+        let song = Song {
+            name: String::from("song"),
+            scenes: vec![scene("first"), scene("second")],
+            selected_scene: 0,
+            pending_scene: None,
+            timeline: Vec::new(),
+            active_cue: None,
+            notes: [None; 128],
+        };
+        let mut show = Show {
+            name: String::from("show"),
+            songs: vec![song],
+            universes: vec![1],
+            selected_song: 0,
+            selected_tempo: DEFAULT_TEMPO,
+            beat_grid: BeatGrid::new(DEFAULT_TEMPO),
+            controls: [None; 128],
+            off: false,
+        };
+        show.beat_grid.align(1001, Instant::now());
+        show.songs[0].scenes[0].start_beat = 1000.0;
+        let mut patch = update();
+        patch.scene = Some(1);
+        show.update_state(patch, config);
+        assert_eq!(show.songs[0].pending_scene.map(|(scene, beat)| (scene, beat.round())), Some((1, 1004.0)));
+        show
+    }
+
+    #[test]
+    fn start_selects_a_pending_scene_on_the_downbeat() {
+        let mut config = BaseConfig::default();
+        config.scene_quantize = String::from("bar");
+        let mut show = show_with_pending_scene(&config);
+
+        let mut patch = update();
+        patch.transport = Some(Transport::Start);
+        show.update_state(patch, &config);
+
+        assert_eq!(show.get_selected_scene(), Some(1));
+        assert!(show.songs[0].pending_scene.is_none());
+        assert_eq!(show.songs[0].scenes[1].start_beat, 0.0);
+    }
+
+    #[test]
+    fn locates_move_pending_scenes_and_scene_starts_along() {
+        let mut config = BaseConfig::default();
+        config.scene_quantize = String::from("bar");
+        let mut show = show_with_pending_scene(&config);
+
+        // The clock locates back to beat 1 while the change is pending
+        let mut patch = update();
+        patch.beat = Some((1, Instant::now()));
+        show.update_state(patch, &config);
+
+        let song = &show.songs[0];
+        assert_eq!(song.selected_scene, 0);
+        let (_, switch_beat) = song.pending_scene.unwrap();
+        assert!((switch_beat - 4.0).abs() < 0.1);
+        assert!((song.scenes[0].start_beat - 0.0).abs() < 0.1);
+        assert!(show.beat_grid.time_of(song.scenes[0].start_beat) <= Instant::now());
+    }
+
+    #[test]
+    fn channels_are_numbered_from_1() {
+        let dir = scene_dir("channels");
+        let path = dir.join("01_channels.yml");
+        write(&path, "faders:\n  0:\n    value: 1\n  1:\n    value: 10\n  512:\n    value: 20\n  513:\n    value: 30\n").unwrap();
+        let mut scene = load_scene_from_path(&path).unwrap();
+        assert_eq!(scene.faders.len(), 2);
+
+        let config = BaseConfig::default();
+        scene.update_state(update(), &config, &BeatGrid::new(DEFAULT_TEMPO));
+        let universe = scene.get_dmx_data()[&1];
+        assert_eq!(universe[0], 10);
+        assert_eq!(universe[511], 20);
+        assert_eq!(universe.iter().filter(|value| **value != 0).count(), 2);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn scene_file_is_replaced_without_leftovers() {
+        let dir = scene_dir("write-scene");
+        let path = dir.join("01_intro.yml");
+        write(&path, "01_name: Intro\nfaders:\n  1:\n    value: 10\n").unwrap();
+        let mut yaml_data: Mapping = from_reader(File::open(&path).unwrap()).unwrap();
+        yaml_data.insert(Value::from("01_name"), Value::from("Learned"));
+
+        write_scene_file(&path, &yaml_data).unwrap();
+
+        let scene = load_scene_from_path(&path).unwrap();
+        assert_eq!(scene.name, "Learned");
+        assert_eq!(scene.faders.len(), 1);
+        assert_eq!(read_dir(&dir).unwrap().count(), 1);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn captures_never_overwrite_each_other() {
+        let dir = scene_dir("capture");
+        let mut input = [0; UNIVERSE_SIZE];
+        input[0] = 10;
+        for _ in 0..3 {
+            capture_scene(dir.to_str().unwrap(), 1, &input);
+        }
+        let captured: Vec<PathBuf> = read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(captured.len(), 3);
+        for path in captured {
+            let scene = load_scene_from_path(&path).unwrap();
+            assert_eq!(scene.name, path.file_stem().unwrap().to_str().unwrap());
+            assert_eq!(scene.faders.len(), 1);
+        }
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_scene_is_skipped() {
+        let dir = scene_dir("broken-scene");
+        let path = dir.join("01_broken.yml");
+        write(&path, "faders: [1, 2\n").unwrap();
+        assert!(load_scene_from_path(&path).is_none());
+        assert!(load_scene_from_path(&dir.join("02_missing.yml")).is_none());
+        remove_dir_all(&dir).unwrap();
+    }
 }
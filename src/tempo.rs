@@ -0,0 +1,339 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use log::{debug};
+
+const TICKS_PER_BEAT: usize = 24; // MIDI clock is sent with 24 pulses per quarter note
+const TICKS_PER_SIXTEENTH: u64 = 6; // song position pointers count in sixteenth notes
+const MIN_GRID_TEMPO: f64 = 1.0; // avoids an endless beat duration for a tempo of 0
+const MIN_TAPS: usize = 2; // two taps are needed for the first interval
+const MAX_TICK_INTERVAL: Duration = Duration::from_millis(500); // longer gaps mean the clock stopped, i.e. below 5 BPM
+pub const QUANTIZE_SETTINGS: [&str; 3] = ["off", "beat", "bar"];
+
+// Derives the tempo from MIDI clock ticks. The tick intervals are averaged over one beat to smooth out jitter.
+pub struct MidiClock {
+    running: bool,
+    ticks: VecDeque<Instant>,
+    next_tick: u64,
+    last_beat: Option<(u64, Instant)>,
+}
+
+impl MidiClock {
+    pub fn new() -> MidiClock {
+        MidiClock {
+            running: true,
+            ticks: VecDeque::with_capacity(TICKS_PER_BEAT + 1),
+            next_tick: 0,
+            last_beat: None,
+        }
+    }
+
+    pub fn tick(&mut self, time: Instant) {
+        if !self.running {
+            return;
+        }
+        if let Some(last_tick) = self.ticks.back() {
+            if time.saturating_duration_since(*last_tick) > MAX_TICK_INTERVAL {
+                self.ticks.clear();
+            }
+        }
+        self.ticks.push_back(time);
+        if self.ticks.len() > TICKS_PER_BEAT + 1 {
+            self.ticks.pop_front();
+        }
+        let (beat, beat_tick) = (self.next_tick / TICKS_PER_BEAT as u64, self.next_tick % TICKS_PER_BEAT as u64);
+        if beat_tick == 0 {
+            self.last_beat = Some((beat, time));
+        }
+        self.next_tick += 1;
+    }
+
+    pub fn start(&mut self) {
+        debug!("MIDI clock: start");
+        self.running = true;
+        self.ticks.clear();
+        // the first tick after start is the downbeat of the song
+        self.next_tick = 0;
+    }
+
+    pub fn set_song_position(&mut self, sixteenths: u16) {
+        debug!("MIDI clock: song position {}", sixteenths);
+        self.next_tick = sixteenths as u64 * TICKS_PER_SIXTEENTH;
+    }
+
+    // Returns the number and time of the latest beat since the last call
+    pub fn take_beat(&mut self) -> Option<(u64, Instant)> {
+        self.last_beat.take()
+    }
+
+    pub fn stop(&mut self) {
+        debug!("MIDI clock: stop");
+        self.running = false;
+    }
+
+    pub fn resume(&mut self) {
+        debug!("MIDI clock: continue");
+        self.running = true;
+        self.ticks.clear();
+    }
+
+    // A clock that vanished without sending stop, e.g. because the cable got pulled, no longer reports a tempo
+    pub fn get_tempo(&self, time: Instant) -> Option<f64> {
+        // Wait for a full beat of ticks before reporting a tempo
+        if !self.running || self.ticks.len() <= TICKS_PER_BEAT {
+            return None;
+        }
+        let last_tick = *self.ticks.back()?;
+        if time.saturating_duration_since(last_tick) > MAX_TICK_INTERVAL {
+            return None;
+        }
+        let beat_duration = last_tick.saturating_duration_since(*self.ticks.front()?);
+        if beat_duration.is_zero() {
+            return None;
+        }
+        Some(60.0 / beat_duration.as_secs_f64())
+    }
+}
+
+// Derives the tempo from taps on a MIDI note or control. The intervals are averaged over the last taps,
+// a pause longer than the timeout starts a new measurement.
+pub struct TapTempo {
+    taps: VecDeque<Instant>,
+    max_taps: usize,
+    timeout: Duration,
+}
+
+impl TapTempo {
+    pub fn new(max_taps: u8, timeout_ms: u64) -> TapTempo {
+        let max_taps = (max_taps as usize).max(MIN_TAPS);
+        TapTempo {
+            taps: VecDeque::with_capacity(max_taps + 1),
+            max_taps,
+            timeout: Duration::from_millis(timeout_ms),
+        }
+    }
+
+    // Returns the tempo as soon as there are enough taps to measure it
+    pub fn tap(&mut self, time: Instant) -> Option<f64> {
+        if let Some(last_tap) = self.taps.back() {
+            if time.saturating_duration_since(*last_tap) > self.timeout {
+                debug!("Tap tempo: reset after pause");
+                self.taps.clear();
+            }
+        }
+        self.taps.push_back(time);
+        if self.taps.len() > self.max_taps {
+            self.taps.pop_front();
+        }
+        if self.taps.len() < MIN_TAPS {
+            return None;
+        }
+        let tapped_duration = self.taps.back()?.saturating_duration_since(*self.taps.front()?);
+        if tapped_duration.is_zero() {
+            return None;
+        }
+        let beat_duration = tapped_duration.as_secs_f64() / (self.taps.len() - 1) as f64;
+        Some(60.0 / beat_duration)
+    }
+}
+
+// Keeps track of the musical position in beats so scenes can be aligned to beats and bars.
+// The grid is anchored to a known beat and follows tempo changes without jumping.
+pub struct BeatGrid {
+    anchor: Instant,
+    anchor_beat: f64,
+    beats_per_minute: f64,
+}
+
+impl BeatGrid {
+    pub fn new(beats_per_minute: f64) -> BeatGrid {
+        BeatGrid {
+            anchor: Instant::now(),
+            anchor_beat: 0.0,
+            beats_per_minute: beats_per_minute.max(MIN_GRID_TEMPO),
+        }
+    }
+
+    pub fn set_tempo(&mut self, beats_per_minute: f64, time: Instant) {
+        self.anchor_beat = self.position(time);
+        self.anchor = time;
+        self.beats_per_minute = beats_per_minute.max(MIN_GRID_TEMPO);
+    }
+
+    pub fn align(&mut self, beat: u64, time: Instant) {
+        self.anchor_beat = beat as f64;
+        self.anchor = time;
+    }
+
+    pub fn position(&self, time: Instant) -> f64 {
+        let beats_per_second = self.beats_per_minute / 60.0;
+        if time >= self.anchor {
+            self.anchor_beat + time.duration_since(self.anchor).as_secs_f64() * beats_per_second
+        } else {
+            self.anchor_beat - self.anchor.duration_since(time).as_secs_f64() * beats_per_second
+        }
+    }
+
+    pub fn time_of(&self, beat: f64) -> Instant {
+        let offset = Duration::from_secs_f64((beat - self.anchor_beat).abs() * 60.0 / self.beats_per_minute);
+        if beat >= self.anchor_beat {
+            self.anchor + offset
+        } else {
+            self.anchor.checked_sub(offset).unwrap_or(self.anchor)
+        }
+    }
+}
+
+// Translates the 'off', 'beat' and 'bar' config settings into a number of beats
+pub fn quantize_unit(setting: &str, beats_per_bar: u8) -> Option<f64> {
+    match setting {
+        "beat" => Some(1.0),
+        "bar" => Some(beats_per_bar.max(1) as f64),
+        _ => None,
+    }
+}
+
+pub fn previous_boundary(position: f64, unit: f64) -> f64 {
+    (position / unit).floor() * unit
+}
+
+pub fn next_boundary(position: f64, unit: f64) -> f64 {
+    (position / unit).ceil() * unit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn clock_reports_the_tempo_after_a_beat_of_ticks() {
+        let mut clock = MidiClock::new();
+        let start = Instant::now();
+        // 125 BPM are 480ms per beat, i.e. a tick every 20ms
+        let tick_interval = ms(20);
+        for tick in 0..TICKS_PER_BEAT as u32 {
+            clock.tick(start + tick_interval * tick);
+        }
+        assert_eq!(clock.get_tempo(start + ms(480)), None);
+        clock.tick(start + ms(480));
+        assert!((clock.get_tempo(start + ms(480)).unwrap() - 125.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn clock_without_ticks_reports_no_tempo() {
+        let mut clock = MidiClock::new();
+        let start = Instant::now();
+        for tick in 0..=TICKS_PER_BEAT as u64 {
+            clock.tick(start + ms(tick * 20));
+        }
+        let last_tick = start + ms(TICKS_PER_BEAT as u64 * 20);
+        assert!(clock.get_tempo(last_tick + MAX_TICK_INTERVAL).is_some());
+        assert_eq!(clock.get_tempo(last_tick + MAX_TICK_INTERVAL + ms(1)), None);
+    }
+
+    #[test]
+    fn clock_counts_beats_from_start_and_song_position() {
+        let mut clock = MidiClock::new();
+        let start = Instant::now();
+        clock.start();
+        clock.tick(start);
+        assert_eq!(clock.take_beat(), Some((0, start)));
+        assert_eq!(clock.take_beat(), None);
+
+        // 8 sixteenths are two beats
+        clock.set_song_position(8);
+        clock.tick(start + ms(10));
+        assert_eq!(clock.take_beat(), Some((2, start + ms(10))));
+        for tick in 1..TICKS_PER_BEAT as u64 {
+            clock.tick(start + ms(10 + tick));
+        }
+        assert_eq!(clock.take_beat(), None);
+        clock.tick(start + ms(40));
+        assert_eq!(clock.take_beat(), Some((3, start + ms(40))));
+    }
+
+    #[test]
+    fn stopped_clock_ignores_ticks() {
+        let mut clock = MidiClock::new();
+        let start = Instant::now();
+        clock.stop();
+        for tick in 0..=TICKS_PER_BEAT as u64 {
+            clock.tick(start + ms(tick * 20));
+        }
+        assert_eq!(clock.get_tempo(start + ms(480)), None);
+        assert_eq!(clock.take_beat(), None);
+    }
+
+    #[test]
+    fn beat_grid_follows_tempo_changes_without_jumping() {
+        let start = Instant::now();
+        let mut grid = BeatGrid::new(120.0);
+        grid.align(0, start);
+        assert!((grid.position(start + ms(1000)) - 2.0).abs() < 1e-9);
+        grid.set_tempo(60.0, start + ms(1000));
+        assert!((grid.position(start + ms(1000)) - 2.0).abs() < 1e-9);
+        assert!((grid.position(start + ms(2000)) - 3.0).abs() < 1e-9);
+        assert!((grid.position(start + ms(500)) - 1.5).abs() < 1e-9);
+        assert_eq!(grid.time_of(3.0), start + ms(2000));
+        assert_eq!(grid.time_of(1.0), start);
+    }
+
+    #[test]
+    fn beat_grid_tempo_has_a_minimum() {
+        let start = Instant::now();
+        let mut grid = BeatGrid::new(0.0);
+        grid.align(4, start);
+        assert!((grid.position(start + ms(60_000)) - 5.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn quantize_units() {
+        assert_eq!(quantize_unit("off", 4), None);
+        assert_eq!(quantize_unit("beat", 4), Some(1.0));
+        assert_eq!(quantize_unit("bar", 3), Some(3.0));
+        assert_eq!(quantize_unit("bar", 0), Some(1.0));
+    }
+
+    #[test]
+    fn boundaries_keep_positions_on_a_boundary() {
+        assert_eq!(previous_boundary(5.5, 4.0), 4.0);
+        assert_eq!(next_boundary(5.5, 4.0), 8.0);
+        assert_eq!(previous_boundary(8.0, 4.0), 8.0);
+        assert_eq!(next_boundary(8.0, 4.0), 8.0);
+        assert_eq!(previous_boundary(-0.5, 1.0), -1.0);
+        assert_eq!(next_boundary(-0.5, 1.0), 0.0);
+    }
+
+    #[test]
+    fn tap_tempo_averages_the_last_taps() {
+        let start = Instant::now();
+        let mut tap_tempo = TapTempo::new(3, 2000);
+        assert_eq!(tap_tempo.tap(start), None);
+        assert_eq!(tap_tempo.tap(start + ms(500)), Some(120.0));
+        assert_eq!(tap_tempo.tap(start + ms(1500)), Some(80.0));
+        // The first tap drops out of the last three
+        assert_eq!(tap_tempo.tap(start + ms(2500)), Some(60.0));
+    }
+
+    #[test]
+    fn tap_tempo_starts_over_after_a_pause() {
+        let start = Instant::now();
+        let mut tap_tempo = TapTempo::new(4, 1000);
+        tap_tempo.tap(start);
+        assert_eq!(tap_tempo.tap(start + ms(500)), Some(120.0));
+        assert_eq!(tap_tempo.tap(start + ms(1600)), None);
+        assert_eq!(tap_tempo.tap(start + ms(2600)), Some(60.0));
+    }
+
+    #[test]
+    fn tap_tempo_needs_two_taps_and_an_interval() {
+        let start = Instant::now();
+        let mut tap_tempo = TapTempo::new(0, 1000);
+        assert_eq!(tap_tempo.tap(start), None);
+        assert_eq!(tap_tempo.tap(start), None);
+        assert_eq!(tap_tempo.tap(start + ms(250)), Some(240.0));
+    }
+}
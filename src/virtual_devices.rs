@@ -0,0 +1,53 @@
+use std::collections::BTreeMap;
+use log::{info};
+use crate::configuration::BaseConfig;
+use crate::dmx_outputs::{DmxOutput, frame_for};
+use crate::shows::{DmxUniverses, UNIVERSE_SIZE};
+
+// Accepts frames without any hardware, e.g. for rehearsals, scene authoring or CI
+pub struct VirtualDmx {
+    universes: Vec<u16>,
+    print_changes: bool,
+    last_frames: BTreeMap<u16, [u8; UNIVERSE_SIZE]>,
+}
+
+impl VirtualDmx {
+    pub fn new(universes: Vec<u16>, print_changes: bool) -> VirtualDmx {
+        VirtualDmx {
+            universes,
+            print_changes,
+            last_frames: BTreeMap::new(),
+        }
+    }
+}
+
+impl DmxOutput for VirtualDmx {
+    fn write(&mut self, universes: &DmxUniverses) {
+        if !self.print_changes {
+            return;
+        }
+        for universe in self.universes.iter() {
+            let frame = frame_for(universes, *universe);
+            let last_frame = self.last_frames.entry(*universe).or_insert([0; UNIVERSE_SIZE]);
+            let changes: Vec<String> = frame.iter()
+                .zip(last_frame.iter())
+                .enumerate()
+                .filter(|(_, (value, last_value))| value != last_value)
+                .map(|(index, (value, _))| format!("{}={}", index + 1, value))
+                .collect();
+            if !changes.is_empty() {
+                info!("Universe {}: {}", universe, changes.join(" "));
+                last_frame.copy_from_slice(frame);
+            }
+        }
+    }
+
+    fn update_config(&mut self, config: &BaseConfig) {
+        self.print_changes = config.virtual_print_changes;
+    }
+}
+
+pub fn open_virtual_port(config: &BaseConfig) -> VirtualDmx {
+    info!("Opened virtual output:   universes {:?}", config.virtual_universes);
+    VirtualDmx::new(config.virtual_universes.clone(), config.virtual_print_changes)
+}